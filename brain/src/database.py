@@ -2,6 +2,7 @@ from . import types, settings
 import sanic.response
 import pymongo
 import pymongo.cursor
+import pymongo.errors
 import bson
 import asyncio
 import math
@@ -20,6 +21,16 @@ ENTRY_TYPES = {
     types.Exit: EXITS
 }
 
+def create_indexes():
+    """
+    Create the unique indexes that drop retried and replayed payloads.
+
+    Payloads of eyes without ids are left out of the indexes.
+    """
+    for collection in (ZAPS, EXITS):
+        collection.create_index("idempotency_key", unique=True, partialFilterExpression={"idempotency_key": {"$type": "string"}})
+    INTRODUCTIONS.create_index("run_id", unique=True, partialFilterExpression={"run_id": {"$type": "string"}})
+
 async def add_entry(entry: types.Zap | types.Introduction | types.Exit, request: sanic.Request) -> sanic.response.HTTPResponse:
     """
     Add an entry to the database.
@@ -40,24 +51,62 @@ def _add_entry(entry: types.Zap | types.Introduction | types.Exit, request: sani
         entry_dict = entry.to_dict()
         entry_dict["ip"] = request.ip
         entry_dict["exited"] = False
-        uuid = str(collection.insert_one(entry_dict).inserted_id)
+        try:
+            uuid = str(collection.insert_one(entry_dict).inserted_id)
+        except pymongo.errors.DuplicateKeyError:
+            # a retried introduction keeps the uuid assigned the first time
+            uuid = str(collection.find_one({"run_id": entry.run_id}, projection=["_id"])["_id"])
         return types.IntroductionResponse(body=uuid.encode())
-    else:
+
+    try:
         collection.insert_one(entry.to_dict())
+    except pymongo.errors.DuplicateKeyError:
+        # a retry or replay of a payload that was stored before the eye saw the response
+        pass
+
+    # acks are repeated until the eye sees a response, the first outcome is kept
+    for ack in entry.acks or []:
+        if not bson.ObjectId.is_valid(ack.id):
+            continue
+        USER_ACTIONS.update_one({"_id": bson.ObjectId(ack.id), "handled": {"$ne": True}}, {"$set": {"handled": True, "outcome": ack.outcome}})
 
     if isinstance(entry, types.Exit):
         INTRODUCTIONS.update_one({"_id": bson.ObjectId(entry.uuid)}, {"$set": {"exited": True}})
 
-    user_action = USER_ACTIONS.find_one({"uuid": entry.uuid}, sort=[("time", pymongo.DESCENDING)])
+    user_action = USER_ACTIONS.find_one({"uuid": entry.uuid, "handled": False}, sort=[("time", pymongo.ASCENDING)])
 
-    if isinstance(entry, types.Exit) and user_action is not None and not user_action["handled"]:
-        user_action["handled"] = True
-        USER_ACTIONS.update_one({"_id": bson.ObjectId(user_action["_id"])}, {"$set": user_action})
+    if isinstance(entry, types.Exit) and user_action is not None:
+        USER_ACTIONS.update_many({"uuid": entry.uuid, "handled": False}, {"$set": {"handled": True}})
 
-    elif user_action is None or user_action["handled"]:
+    elif user_action is None:
         return types.NormalResponse()
 
-    return types.CommandResponse(body=user_action["action"].encode())
+    # unacknowledged commands are resent, the eye only applies each id once
+    return types.CommandResponse(command=types.ActionRequest.to_command(user_action))
+
+async def wait_for_command(uuid: str, wait: float) -> sanic.response.HTTPResponse:
+    """
+    Wait up to `wait` seconds for a command that has not been pushed to the eye yet.
+
+    Pushed commands are still resent on telemetry responses until acknowledged.
+    """
+    deadline = asyncio.get_running_loop().time() + wait
+
+    while True:
+        user_action = await asyncio.to_thread(
+            USER_ACTIONS.find_one_and_update,
+            {"uuid": uuid, "handled": False, "pushed": {"$ne": True}},
+            {"$set": {"pushed": True}},
+            sort=[("time", pymongo.ASCENDING)],
+        )
+
+        if user_action is not None:
+            return types.CommandResponse(command=types.ActionRequest.to_command(user_action))
+
+        if asyncio.get_running_loop().time() >= deadline:
+            return types.NormalResponse()
+
+        await asyncio.sleep(settings.COMMAND_POLL_INTERVAL)
 
 async def async_introduction_find(*args, **kwargs) -> pymongo.cursor.Cursor:
     """
@@ -141,7 +190,7 @@ async def get_introduction(uuid: str) -> types.IntroductionUIResponse:
     """
     intro_dict = (await async_introduction_find(
         {"_id": bson.ObjectId(uuid)},
-        projection={"host": 1, "ip": 1, "pid": 1, "parent_pid": 1, "name": 1, "user": 1, "args": 1, "time": 1, "_id": 1, "display_name": 1}
+        projection={"host": 1, "ip": 1, "pid": 1, "parent_pid": 1, "name": 1, "user": 1, "args": 1, "time": 1, "_id": 1, "display_name": 1, "run_id": 1, "session_id": 1, "restart_index": 1}
     ))[0]
     intro_dict["uuid"] = intro_dict.pop("_id").binary.hex()
     intro_dict["created_time"] = intro_dict.pop("time")
@@ -152,13 +201,28 @@ async def get_status(uuid: str) -> types.StatusResponse:
     """
     Get status for a given UUID.
     """
-    intro_dict = (await async_introduction_find({"_id": bson.ObjectId(uuid)}, projection={"exited": 1, "_id": 0}))[0]
+    intro_dict = (await async_introduction_find({"_id": bson.ObjectId(uuid)}, projection={"exited": 1, "sequence": 1, "_id": 0}))[0]
     status_dict = {
         "exited": intro_dict["exited"],
+        "missing": await asyncio.to_thread(_missing_payloads, uuid, intro_dict.get("sequence")),
     }
 
     return types.StatusResponse(**status_dict)
 
+def _missing_payloads(uuid: str, introduction_sequence: int | None) -> int | None:
+    """
+    Count the gaps in the sequence numbers up to the latest payload received.
+    """
+    query = {"uuid": uuid, "sequence": {"$ne": None}}
+    sequences = set(ZAPS.distinct("sequence", query)) | set(EXITS.distinct("sequence", query))
+    if introduction_sequence is not None:
+        sequences.add(introduction_sequence)
+
+    if not sequences:
+        return None
+
+    return max(sequences) + 1 - len(sequences)
+
 async def get_metrics(ui_request: types.UIRequest) -> list[types.MetricsResponseStructure]:
     """
     Get metrics for a given UUID.
@@ -166,7 +230,7 @@ async def get_metrics(ui_request: types.UIRequest) -> list[types.MetricsResponse
     metrics_dict = await asyncio.to_thread(ZAPS.find,
         ui_request.to_query_dict(),
         sort=[("time", pymongo.ASCENDING)],
-        projection={"cpu": 1, "memory": 1, "disk": 1, "time": 1, "_id": 0},
+        projection={"cpu": 1, "memory": 1, "disk": 1, "time": 1, "extended": 1, "_id": 0},
     )
 
     metrics_dicts = list(metrics_dict)
@@ -3,7 +3,7 @@ import base64
 
 import sanic.middleware
 
-from . import settings
+from . import settings, types
 
 AUTH_B64 = base64.b64encode(f'{settings.USER_NAME}:{settings.PASSWORD}'.encode()).decode()
 UNAUTH_RESPONSE = sanic.HTTPResponse("User is not authorized", status=401)
@@ -24,3 +24,12 @@ async def basic_auth_func(request: sanic.Request):
         return UNAUTH_RESPONSE
 
 basic_auth = sanic.middleware.Middleware(func=basic_auth_func, location=sanic.middleware.MiddlewareLocation.REQUEST)
+
+async def protocol_headers_func(request: sanic.Request, response: sanic.HTTPResponse):
+    if not request.path.startswith("/telemetry/"):
+        return
+
+    response.headers["X-BB-Protocol-Version"] = str(types.PROTOCOL_VERSION)
+    response.headers["X-BB-Encodings"] = ",".join(types.ENCODINGS)
+
+protocol_headers = sanic.middleware.Middleware(func=protocol_headers_func, location=sanic.middleware.MiddlewareLocation.RESPONSE)
@@ -1,9 +1,9 @@
+import asyncio
 import sanic
 import sanic.response
-import msgpack
 import sanic_cors
 
-from . import types, database, middleware
+from . import types, database, middleware, settings
 
 app = sanic.Sanic("Brain")
 
@@ -13,8 +13,16 @@ app.blueprint(telemetry)
 ui = sanic.Blueprint("ui", url_prefix="/ui")
 app.blueprint(ui)
 app.middleware(middleware_or_request=middleware.basic_auth, attach_to="request")
+app.middleware(middleware_or_request=middleware.protocol_headers, attach_to="response")
 sanic_cors.CORS(app)
 
+@app.before_server_start
+async def create_indexes(app: sanic.Sanic):
+    """
+    Create the database indexes before taking requests.
+    """
+    await asyncio.to_thread(database.create_indexes)
+
 ### TELEMETRY ###
 
 @telemetry.route("/zap", methods=["POST"])
@@ -22,8 +30,7 @@ async def zap(request: sanic.Request):
     """
     Record a zap.
     """
-    payload = msgpack.unpackb(request.body)
-    zap = types.Zap(**payload)
+    zap = types.Zap.from_request(request)
 
     return await database.add_entry(entry=zap, request=request)
 
@@ -32,8 +39,7 @@ async def introduction_tel(request: sanic.Request):
     """
     Record an introduction.
     """
-    payload = msgpack.unpackb(request.body)
-    introduction = types.Introduction(**payload)
+    introduction = types.Introduction.from_request(request)
 
     return await database.add_entry(entry=introduction, request=request)
 
@@ -42,11 +48,19 @@ async def exit_route(request: sanic.Request):
     """
     Record an exit.
     """
-    payload = msgpack.unpackb(request.body)
-    exito = types.Exit(**payload)
+    exito = types.Exit.from_request(request)
 
     return await database.add_entry(entry=exito, request=request)
 
+@telemetry.route("/commands/<uuid>", methods=["GET"])
+async def commands(request: sanic.Request, uuid: str):
+    """
+    Long-poll for the next command for a given UUID.
+    """
+    wait = min(float(request.args.get("wait", settings.COMMAND_POLL_WAIT)), settings.COMMAND_POLL_WAIT)
+
+    return await database.wait_for_command(uuid=uuid, wait=wait)
+
 ### UI - listings ###
 
 @ui.route("/eyeballs", methods=["GET"])
@@ -154,7 +168,8 @@ async def action(request: sanic.Request, uuid: str, action: str):
     """
     Perform an action on a given UUID.
     """
-    action_request = types.ActionRequest(uuid=uuid, action=action)
+    arguments = {key: value for key, value in request.args.items() for value in value[:1]}
+    action_request = types.ActionRequest(uuid=uuid, action=action, arguments=arguments)
 
     await database.perform_action(action_request=action_request)
 
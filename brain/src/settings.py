@@ -4,3 +4,5 @@ MONGO_URI = os.getenv("MONGO_URI")
 LARGEST_METRICS_RESPONSE = int(os.getenv("LARGEST_METRICS_RESPONSE", 1000))
 USER_NAME = os.getenv("BB_USER_NAME", "default")
 PASSWORD = os.getenv("BB_PASSWORD", "default")
+COMMAND_POLL_WAIT = float(os.getenv("COMMAND_POLL_WAIT", 30))
+COMMAND_POLL_INTERVAL = float(os.getenv("COMMAND_POLL_INTERVAL", 0.5))
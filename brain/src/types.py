@@ -2,17 +2,83 @@ import dataclasses
 import dataclasses_json
 import datetime
 import typing
+import json
+import logging
+import sanic.exceptions
 import sanic.response
+import msgpack
+
+try:
+    import cbor2
+except ImportError:
+    cbor2 = None
+
+logger = logging.getLogger(__name__)
+
+# Wire protocol version understood by this brain, eyes send theirs in every payload
+PROTOCOL_VERSION = 2
+
+DECODERS = {
+    "application/msgpack": msgpack.unpackb,
+    "application/json": json.loads,
+}
+if cbor2 is not None:
+    DECODERS["application/cbor"] = cbor2.loads
+
+# Announced to the eyes in the X-BB-Encodings header
+ENCODINGS = [content_type.split("/")[1] for content_type in DECODERS]
 
 class Entry():
     def to_dict(self) -> dict:
         return dataclasses.asdict(self)
 
+    @classmethod
+    def from_request(cls, request: sanic.Request):
+        """
+        Decode a payload by its content type, msgpack if none is given.
+
+        Fields this brain does not know are dropped from payloads of newer eyes, so
+        additions to the protocol do not break older brains.
+        """
+        content_type = (request.content_type or "application/msgpack").split(";")[0].strip().lower()
+        decoder = DECODERS.get(content_type)
+        if decoder is None:
+            raise sanic.exceptions.SanicException(f"Unsupported encoding {content_type}", status_code=415)
+
+        payload = decoder(request.body)
+        return cls.from_payload(payload, payload.get("protocol_version"))
+
+    @classmethod
+    def from_payload(cls, payload: dict, protocol_version: typing.Optional[int]):
+        """
+        Build an entry from a decoded payload, or a part of one, dropping unknown fields of newer eyes.
+        """
+        if (protocol_version or 0) > PROTOCOL_VERSION:
+            known = {field.name for field in dataclasses.fields(cls)}
+            unknown = set(payload) - known
+            if unknown:
+                logger.warning("Dropping %s fields %s of protocol version %s", cls.__name__, sorted(unknown), protocol_version)
+            payload = {key: value for key, value in payload.items() if key in known}
+
+        return cls(**payload)
+
 @dataclasses.dataclass
 class MessageBuffer(Entry, dataclasses_json.DataClassJsonMixin):
     message: str
     timestamp: int
     error: bool
+    # label or path of the tailed file the line was read from
+    source: typing.Optional[str] = None
+
+@dataclasses.dataclass
+class CommandAck(Entry, dataclasses_json.DataClassJsonMixin):
+    """
+    An eye's acknowledgement of a command, with the outcome of applying it.
+    """
+    id: str
+    outcome: str
+    time: int
+    message: typing.Optional[str] = None
 
 @dataclasses.dataclass
 class Zap(Entry):
@@ -24,11 +90,26 @@ class Zap(Entry):
     cpu: float
     time: int
     disk: typing.Optional[int] = None
+    data: typing.Optional[typing.List[dict]] = None
     messages: typing.Optional[typing.List[MessageBuffer]] = None
+    extended: typing.Optional[dict] = None
+    host: typing.Optional[dict] = None
+    custom: typing.Optional[dict] = None
+    acks: typing.Optional[typing.List[CommandAck]] = None
+    eye: typing.Optional[dict] = None
+    run_id: typing.Optional[str] = None
+    session_id: typing.Optional[str] = None
+    restart_index: typing.Optional[int] = None
+    labels: typing.Optional[typing.Dict[str, str]] = None
+    protocol_version: typing.Optional[int] = None
+    sequence: typing.Optional[int] = None
+    idempotency_key: typing.Optional[str] = None
 
     def __post_init__(self):
         if self.messages is not None:
-            self.messages = [MessageBuffer(**m) for m in self.messages]
+            self.messages = [MessageBuffer.from_payload(m, self.protocol_version) for m in self.messages]
+        if self.acks is not None:
+            self.acks = [CommandAck.from_payload(a, self.protocol_version) for a in self.acks]
 
 @dataclasses.dataclass
 class Introduction(Entry):
@@ -43,6 +124,28 @@ class Introduction(Entry):
     user: str
     time: int
     display_name: typing.Optional[str] = None
+    run_id: typing.Optional[str] = None
+    session_id: typing.Optional[str] = None
+    restart_index: typing.Optional[int] = None
+    machine_id: typing.Optional[str] = None
+    boot_id: typing.Optional[str] = None
+    os_version: typing.Optional[str] = None
+    kernel_version: typing.Optional[str] = None
+    arch: typing.Optional[str] = None
+    ip_addresses: typing.Optional[typing.List[str]] = None
+    container_id: typing.Optional[str] = None
+    container_runtime: typing.Optional[str] = None
+    kubernetes_pod: typing.Optional[str] = None
+    kubernetes_namespace: typing.Optional[str] = None
+    executable: typing.Optional[str] = None
+    executable_sha256: typing.Optional[str] = None
+    eye_version: typing.Optional[str] = None
+    features: typing.Optional[typing.List[str]] = None
+    labels: typing.Optional[typing.Dict[str, str]] = None
+    protocol_version: typing.Optional[int] = None
+    encodings: typing.Optional[typing.List[str]] = None
+    sequence: typing.Optional[int] = None
+    idempotency_key: typing.Optional[str] = None
 
 @dataclasses.dataclass
 class Exit(Entry):
@@ -53,10 +156,21 @@ class Exit(Entry):
     exit_code: int
     time: int
     messages: typing.Optional[typing.List[MessageBuffer]] = None
+    acks: typing.Optional[typing.List[CommandAck]] = None
+    eye: typing.Optional[dict] = None
+    run_id: typing.Optional[str] = None
+    session_id: typing.Optional[str] = None
+    restart_index: typing.Optional[int] = None
+    summary: typing.Optional[dict] = None
+    protocol_version: typing.Optional[int] = None
+    sequence: typing.Optional[int] = None
+    idempotency_key: typing.Optional[str] = None
 
     def __post_init__(self):
         if self.messages is not None:
-            self.messages = [MessageBuffer(**m) for m in self.messages]
+            self.messages = [MessageBuffer.from_payload(m, self.protocol_version) for m in self.messages]
+        if self.acks is not None:
+            self.acks = [CommandAck.from_payload(a, self.protocol_version) for a in self.acks]
 
 class NormalResponse(sanic.response.HTTPResponse):
     def __init__(self, **kwargs):
@@ -67,8 +181,8 @@ class IntroductionResponse(sanic.response.HTTPResponse):
         super().__init__(status=201, **kwargs)
 
 class CommandResponse(sanic.response.HTTPResponse):
-    def __init__(self, **kwargs):
-        super().__init__(status=200, **kwargs)
+    def __init__(self, command: dict, **kwargs):
+        super().__init__(status=200, body=msgpack.packb(command), content_type="application/msgpack", **kwargs)
 
 @dataclasses.dataclass
 class UIRequest:
@@ -114,8 +228,22 @@ class ActionRequest(dataclasses_json.DataClassJsonMixin):
     """
     uuid: str
     action: str
+    arguments: dict = dataclasses.field(default_factory=dict)
     time: int = dataclasses.field(default_factory=lambda: int(datetime.datetime.now().timestamp()))
     handled: bool = False
+    outcome: typing.Optional[str] = None
+
+    @staticmethod
+    def to_command(user_action: dict) -> dict:
+        """
+        Build the command envelope sent to the eye from a stored user action.
+        """
+        return {
+            "id": str(user_action["_id"]),
+            "kind": user_action["action"],
+            "arguments": user_action.get("arguments", {}),
+            "issued_at": user_action["time"] * 1000,
+        }
 
 @dataclasses.dataclass
 class IntroductionUIResponse(dataclasses_json.DataClassJsonMixin):
@@ -132,6 +260,9 @@ class IntroductionUIResponse(dataclasses_json.DataClassJsonMixin):
     args: str
     created_time: int
     display_name: typing.Optional[str] = None
+    run_id: typing.Optional[str] = None
+    session_id: typing.Optional[str] = None
+    restart_index: typing.Optional[int] = None
 
 @dataclasses.dataclass
 class StatusResponse(dataclasses_json.DataClassJsonMixin):
@@ -139,6 +270,8 @@ class StatusResponse(dataclasses_json.DataClassJsonMixin):
     A response to get the status of a given UUID.
     """
     exited: bool
+    # payloads the eye numbered but the brain never received, None for eyes without sequence numbers
+    missing: typing.Optional[int] = None
 
 @dataclasses.dataclass
 class MetricsResponseStructure(dataclasses_json.DataClassJsonMixin):
@@ -149,6 +282,7 @@ class MetricsResponseStructure(dataclasses_json.DataClassJsonMixin):
     memory: float
     disk: float
     time: int
+    extended: typing.Optional[dict] = None
 
 @dataclasses.dataclass
 class ExitResponse(dataclasses_json.DataClassJsonMixin):
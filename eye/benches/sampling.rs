@@ -0,0 +1,48 @@
+//! Compares the cost of one metrics sample when refreshing the whole system
+//! against refreshing only the monitored process from a long-lived `System`.
+//!
+//! Run with `cargo bench --bench sampling`.
+
+use std::time::{Duration, Instant};
+use sysinfo::{System, Pid, ProcessRefreshKind, ProcessesToUpdate};
+
+const ITERATIONS: u32 = 200;
+
+fn measure(name: &str, mut sample: impl FnMut() -> f64) -> Duration {
+    // warm up so first-refresh setup is not counted
+    sample();
+
+    let start = Instant::now();
+    let mut checksum = 0.0;
+    for _ in 0..ITERATIONS {
+        checksum += sample();
+    }
+    let per_sample = start.elapsed() / ITERATIONS;
+
+    println!(
+        "{:<32} {:>10.3} ms/sample {:>10.0} samples/s (checksum {:.0})",
+        name,
+        per_sample.as_secs_f64() * 1000.0,
+        1.0 / per_sample.as_secs_f64(),
+        checksum,
+    );
+    per_sample
+}
+
+fn main() {
+    let pid = Pid::from_u32(std::process::id());
+
+    let mut sys = System::new_all();
+    let full = measure("refresh_all", || {
+        sys.refresh_all();
+        sys.process(pid).map(|process| process.memory() as f64).unwrap_or(0.0)
+    });
+
+    let mut sys = System::new();
+    let targeted = measure("targeted refresh, reused System", || {
+        sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::nothing().with_memory().with_cpu());
+        sys.process(pid).map(|process| process.memory() as f64).unwrap_or(0.0)
+    });
+
+    println!("speedup: {:.1}x", full.as_secs_f64() / targeted.as_secs_f64());
+}
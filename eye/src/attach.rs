@@ -0,0 +1,174 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::debug;
+use regex::Regex;
+use sysinfo::{Pid, Process, ProcessRefreshKind, ProcessStatus, ProcessesToUpdate, System, UpdateKind, Users};
+use tokio::task::JoinHandle;
+use crate::types::Args;
+
+/// Process `attach` watches, given with `--pid` or `--match`
+pub enum Target {
+    Pid(u32),
+    /// Matched against the name and the command line of every process
+    Match(Regex),
+}
+
+impl Target {
+    pub fn from_args(args: &Args) -> Option<Self> {
+        match (&args.pid, &args.match_process) {
+            (Some(pid), _) => Some(Target::Pid(*pid)),
+            (None, Some(pattern)) => Some(Target::Match(pattern.clone())),
+            (None, None) => None,
+        }
+    }
+
+    fn matches(&self, process: &Process) -> bool {
+        match self {
+            Target::Pid(pid) => process.pid().as_u32() == *pid,
+            Target::Match(pattern) => pattern.is_match(&process.name().to_string_lossy()) || pattern.is_match(&command_line(process)),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Pid(pid) => write!(f, "--pid {}", pid),
+            Target::Match(pattern) => write!(f, "--match {}", pattern),
+        }
+    }
+}
+
+/// A running process the eye did not spawn
+pub struct Attached {
+    pub pid: u32,
+    pub parent_pid: u32,
+    /// Executable path if it can be read, the process name otherwise
+    pub name: String,
+    pub args: String,
+    pub user: Option<String>,
+    /// Tells the process apart from a later one reusing its pid
+    start_time: u64,
+}
+
+impl Attached {
+    /// Find the target among the running processes, the oldest if several match
+    pub fn find(target: &Target) -> Option<Self> {
+        let mut sys = System::new();
+        let refresh_kind = ProcessRefreshKind::nothing()
+            .with_cmd(UpdateKind::OnlyIfNotSet)
+            .with_exe(UpdateKind::OnlyIfNotSet)
+            .with_user(UpdateKind::OnlyIfNotSet);
+        match target {
+            Target::Pid(pid) => sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[Pid::from_u32(*pid)]), true, refresh_kind),
+            Target::Match(_) => sys.refresh_processes_specifics(ProcessesToUpdate::All, true, refresh_kind),
+        };
+
+        let excluded = ancestors(&sys);
+        let process = sys.processes().values()
+            .filter(|process| process.thread_kind().is_none() && !is_gone(process))
+            .filter(|process| !excluded.contains(&process.pid()))
+            .filter(|process| target.matches(process))
+            .min_by_key(|process| (process.start_time(), process.pid()))?;
+
+        let mut cmd = process.cmd().iter().map(|arg| arg.to_string_lossy().to_string());
+        let name = process.exe().map(|exe| exe.to_string_lossy().to_string())
+            .or_else(|| cmd.next())
+            .unwrap_or_else(|| process.name().to_string_lossy().to_string());
+        let user = process.user_id().and_then(|uid| Users::new_with_refreshed_list().get_user_by_id(uid).map(|user| user.name().to_string()));
+
+        Some(Attached {
+            pid: process.pid().as_u32(),
+            parent_pid: process.parent().map_or(0, Pid::as_u32),
+            name,
+            args: cmd.skip(usize::from(process.exe().is_some())).collect::<Vec<_>>().join(" "),
+            user,
+            start_time: process.start_time(),
+        })
+    }
+
+    /// True once the process is gone, a zombie, or its pid belongs to another process
+    pub fn has_exited(&self) -> bool {
+        let mut sys = System::new();
+        sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[Pid::from_u32(self.pid)]), true, ProcessRefreshKind::nothing());
+        match sys.process(Pid::from_u32(self.pid)) {
+            Some(process) => is_gone(process) || process.start_time() != self.start_time,
+            None => true,
+        }
+    }
+}
+
+/// The eye and whatever started it, their command lines hold the pattern too
+fn ancestors(sys: &System) -> Vec<Pid> {
+    let mut ancestors = vec![Pid::from_u32(std::process::id())];
+    while let Some(parent) = ancestors.last().and_then(|pid| sys.process(*pid)).and_then(Process::parent) {
+        if ancestors.contains(&parent) {
+            break;
+        }
+        ancestors.push(parent);
+    }
+    ancestors
+}
+
+fn is_gone(process: &Process) -> bool {
+    matches!(process.status(), ProcessStatus::Zombie | ProcessStatus::Dead)
+}
+
+fn command_line(process: &Process) -> String {
+    process.cmd().iter().map(|arg| arg.to_string_lossy()).collect::<Vec<_>>().join(" ")
+}
+
+/// Set once SIGTERM or SIGINT asks the eye to stop watching, the attached process is left alone.
+///
+/// The signals are only handled while it is alive.
+pub struct StopSignal {
+    stopping: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StopSignal {
+    /// Stop watching on SIGTERM and SIGINT instead of passing them on, the process was not ours to start.
+    /// Without `handle_signals` the flag is never set and the signals are left to the program.
+    pub fn new(handle_signals: bool) -> Self {
+        let stopping = Arc::new(AtomicBool::new(false));
+        let handle = handle_signals.then(|| {
+            let stopping = stopping.clone();
+            tokio::spawn(async move {
+                #[cfg(unix)]
+                {
+                    use tokio::signal::unix::{signal, SignalKind};
+                    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+                    tokio::select! {
+                        _ = sigterm.recv() => {},
+                        _ = tokio::signal::ctrl_c() => {},
+                    }
+                }
+                #[cfg(not(unix))]
+                let _ = tokio::signal::ctrl_c().await;
+
+                debug!("Received a stop signal, detaching from the process");
+                stopping.store(true, Ordering::Relaxed);
+            })
+        });
+
+        StopSignal { stopping, handle }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    /// The flag for a run to check, it outlives the signal handling
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.stopping.clone()
+    }
+}
+
+impl Drop for StopSignal {
+    fn drop(&mut self) {
+        if let Some(handle) = &self.handle {
+            handle.abort();
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::Mutex;
+use log::debug;
+use crate::record::Recorder;
+use crate::telemetry::TelemetryClient;
+use crate::types::{Args, BrainCommand, BrainWaveError, Endpoint, EndpointMode, Introduction, RunIdentity};
+
+/// A telemetry server and the uuid it assigned to the current run
+struct Brain {
+    url: String,
+    uuid: Mutex<Option<String>>,
+}
+
+impl Brain {
+    fn uuid(&self) -> Option<String> {
+        self.uuid.lock().unwrap().clone()
+    }
+}
+
+/// The brains given with `-e`, or the default endpoint
+pub fn endpoints(args: &Args, client: &TelemetryClient) -> Vec<String> {
+    if args.telemetry_endpoint.is_empty() { vec![client.url(None)] } else { args.telemetry_endpoint.clone() }
+}
+
+/// Every telemetry server of the current run.
+///
+/// Each brain assigns its own uuid in response to the introduction, payloads are
+/// stamped with the uuid of the brain they are sent to. A brain that could not be
+/// introduced is introduced again before the next payload for it.
+///
+/// Only the uuids are locked, and never across a request, so the zap task and the
+/// exit report can share the brains through an `Arc` without waiting on each other.
+pub struct Brains {
+    client: TelemetryClient,
+    brains: Vec<Brain>,
+    mode: EndpointMode,
+    run: RunIdentity,
+    introduction: Mutex<Option<Introduction>>,
+    recorder: Option<Recorder>,
+}
+
+impl Brains {
+    pub fn from_args(args: &Args, client: TelemetryClient, run: RunIdentity) -> Self {
+        Brains {
+            brains: endpoints(args, &client).into_iter().map(|url| Brain { url, uuid: Mutex::new(None) }).collect(),
+            client,
+            mode: args.endpoint_mode,
+            run,
+            introduction: Mutex::new(None),
+            recorder: None,
+        }
+    }
+
+    /// Record every payload given to the brains at the time it is sent
+    pub fn with_recorder(mut self, recorder: Option<Recorder>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Introduce the run to every brain in fan-out mode, or to the first that answers in failover mode.
+    ///
+    /// Returns the commands the brains answered with, for the run to apply once it is monitored.
+    pub async fn introduce(&self, introduction: Introduction) -> Result<Vec<BrainCommand>, BrainWaveError> {
+        self.record(&introduction).await;
+        *self.introduction.lock().unwrap() = Some(introduction);
+
+        let mut commands = Vec::new();
+        for brain in &self.brains {
+            if self.introduce_to(brain, &mut commands).await? && self.mode == EndpointMode::Failover {
+                break;
+            }
+        }
+
+        Ok(commands)
+    }
+
+    /// Send a payload, returns the commands the brains answered with.
+    ///
+    /// In failover mode the brains are tried in order until one is healthy after the
+    /// send, in fan-out mode every brain receives the payload.
+    pub async fn send<T: Endpoint + Clone>(&self, payload: &T) -> Result<Vec<BrainCommand>, BrainWaveError> {
+        self.record(payload).await;
+        let mut commands = Vec::new();
+
+        for brain in &self.brains {
+            if brain.uuid().is_none() && !self.introduce_to(brain, &mut commands).await? {
+                continue;
+            }
+
+            let mut payload = payload.clone();
+            payload.set_uuid(&brain.uuid().unwrap_or_default());
+
+            match self.client.send(&payload, Some(&brain.url)).await {
+                Err(BrainWaveError::CommandReceived(command)) => commands.push(command),
+                Err(e) => return Err(e),
+                Ok(_) => {},
+            }
+
+            if self.mode == EndpointMode::Failover && self.client.is_healthy(&brain.url) {
+                break;
+            }
+        }
+
+        Ok(commands)
+    }
+
+    /// Url and uuid of every brain the run has been introduced to
+    pub fn introduced(&self) -> Vec<(String, String)> {
+        self.brains.iter()
+            .filter_map(|brain| brain.uuid().map(|uuid| (brain.url.clone(), uuid)))
+            .collect()
+    }
+
+    pub fn urls(&self) -> Vec<String> {
+        self.brains.iter().map(|brain| brain.url.clone()).collect()
+    }
+
+    pub fn client(&self) -> &TelemetryClient {
+        &self.client
+    }
+
+    pub fn run(&self) -> &RunIdentity {
+        &self.run
+    }
+
+    /// Uuid assigned by the first brain that answered, the run id until one has
+    pub fn uuid(&self) -> String {
+        self.brains.iter().find_map(Brain::uuid).unwrap_or_else(|| self.run.run_id.clone())
+    }
+
+    /// Record the payload once, after a telemetry delay that holds back its send
+    async fn record<T: Endpoint>(&self, payload: &T) {
+        if let Some(recorder) = &self.recorder {
+            self.client.wait_for_delay().await;
+            recorder.record(payload);
+        }
+    }
+
+    /// Returns true if the brain assigned a uuid, a command answered instead is added to `commands`
+    async fn introduce_to(&self, brain: &Brain, commands: &mut Vec<BrainCommand>) -> Result<bool, BrainWaveError> {
+        let Some(introduction) = self.introduction.lock().unwrap().clone() else {
+            return Ok(false);
+        };
+
+        let uuid = match self.client.send(&introduction, Some(&brain.url)).await {
+            Ok(uuid) => uuid,
+            Err(BrainWaveError::CommandReceived(command)) => {
+                commands.push(command);
+                return Ok(false);
+            },
+            Err(e) => return Err(e),
+        };
+        if uuid.is_empty() {
+            return Ok(false);
+        }
+
+        debug!("Setting UUID for {}: {}", brain.url, uuid);
+        *brain.uuid.lock().unwrap() = Some(uuid);
+        Ok(true)
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::debug;
+use crate::types::CircuitState;
+
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    /// When the next probe may be sent while the circuit is not closed
+    next_probe: Instant,
+}
+
+/// Circuit breakers of every telemetry server a client sends to, keyed by base URL
+pub struct Circuits {
+    failures: u32,
+    probe_interval: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl Circuits {
+    /// Open a circuit after `failures` consecutive failures and probe it every `probe_interval`
+    pub fn new(failures: u32, probe_interval: Duration) -> Self {
+        Circuits { failures: failures.max(1), probe_interval, circuits: Mutex::new(HashMap::new()) }
+    }
+
+    /// Whether a request may be sent to `endpoint`.
+    ///
+    /// Once the probe interval of an open circuit has passed a single probe is let
+    /// through, its result decides whether the circuit closes or stays open.
+    pub fn allow(&self, endpoint: &str) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(endpoint) else {
+            return true;
+        };
+
+        if circuit.state == CircuitState::Closed {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now < circuit.next_probe {
+            return false;
+        }
+
+        debug!("Circuit for {} half-open, probing", endpoint);
+        circuit.state = CircuitState::HalfOpen;
+        circuit.next_probe = now + self.probe_interval;
+        true
+    }
+
+    pub fn record_success(&self, endpoint: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(endpoint) {
+            if circuit.state != CircuitState::Closed {
+                debug!("Circuit for {} closed", endpoint);
+            }
+            circuit.state = CircuitState::Closed;
+            circuit.failures = 0;
+        }
+    }
+
+    pub fn record_failure(&self, endpoint: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(endpoint.to_string()).or_insert_with(|| Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            next_probe: Instant::now(),
+        });
+
+        circuit.failures += 1;
+        if circuit.state == CircuitState::HalfOpen || circuit.failures >= self.failures {
+            if circuit.state != CircuitState::Open {
+                debug!("Circuit for {} open after {} failures, probing every {:?}", endpoint, circuit.failures, self.probe_interval);
+            }
+            circuit.state = CircuitState::Open;
+            circuit.next_probe = circuit.next_probe.max(Instant::now() + self.probe_interval);
+        }
+    }
+
+    /// Keep the circuit open until `until`, used when the brain asks for a long pause
+    pub fn open_until(&self, endpoint: &str, until: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(endpoint.to_string()).or_insert_with(|| Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            next_probe: until,
+        });
+
+        debug!("Circuit for {} open for {:?} as requested by the server", endpoint, until.saturating_duration_since(Instant::now()));
+        circuit.state = CircuitState::Open;
+        circuit.next_probe = circuit.next_probe.max(until);
+    }
+
+    /// True unless the last request to `endpoint` failed
+    pub fn is_healthy(&self, endpoint: &str) -> bool {
+        self.circuits.lock().unwrap().get(endpoint).is_none_or(|circuit| circuit.failures == 0 && circuit.state == CircuitState::Closed)
+    }
+
+    pub fn state(&self, endpoint: &str) -> CircuitState {
+        self.circuits.lock().unwrap().get(endpoint).map(|circuit| circuit.state).unwrap_or(CircuitState::Closed)
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use chrono::Utc;
+use log::{debug, error};
+use sysinfo::{Pid, Signal};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::telemetry::TelemetryClient;
+use crate::types::{Args, BrainCommand, CommandKind, CommandAck, CommandOutcome};
+use crate::utils::process_system;
+
+/// Number of applied command ids remembered to recognise redelivered commands
+const APPLIED_HISTORY: usize = 1000;
+
+/// How long the brain holds a command poll open before answering with no command
+const POLL_WAIT: Duration = Duration::from_secs(30);
+
+/// Longest wait between reconnection attempts of the command channel
+const MAX_POLL_BACKOFF: Duration = Duration::from_secs(30);
+
+/// What the monitoring loop has to do after a command was applied
+#[derive(Debug, PartialEq)]
+pub enum CommandEffect {
+    None,
+    Restart,
+    Exit,
+    SetInterval(Duration),
+}
+
+/// Command state shared by every run of the eye.
+///
+/// The brain keeps sending a command until it is acknowledged, so applied ids are
+/// remembered and a redelivered command is only acknowledged again, never re-applied.
+#[derive(Default)]
+pub struct Commands {
+    applied: VecDeque<String>,
+    pending: Vec<CommandAck>,
+    /// Received before the run was monitored, such as in the answer to the introduction
+    queued: Vec<BrainCommand>,
+    /// Exit once the current run finishes instead of restarting
+    pub stop_after_drain: bool,
+    /// Telemetry interval set by the brain, kept across restarts
+    pub interval: Option<f64>,
+}
+
+impl Commands {
+    pub fn apply(&mut self, command: BrainCommand, child_pid: u32) -> CommandEffect {
+        debug!("Applying command: {:?}", command);
+
+        if !command.id.is_empty() && self.applied.contains(&command.id) {
+            self.acknowledge(&command, CommandOutcome::Duplicate, None);
+            return CommandEffect::None;
+        }
+
+        let result = match command.kind {
+            CommandKind::Restart => Ok(CommandEffect::Restart),
+            CommandKind::Exit => Ok(CommandEffect::Exit),
+            CommandKind::Signal => match command.arguments.get("signal") {
+                Some(name) => match parse_signal(name) {
+                    Some(signal) => send_signal(child_pid, signal),
+                    None => Err((CommandOutcome::Rejected, format!("Unknown signal: {}", name))),
+                },
+                None => Err((CommandOutcome::Rejected, "Missing signal argument".to_string())),
+            },
+            CommandKind::Pause => send_signal(child_pid, Signal::Stop),
+            CommandKind::Resume => send_signal(child_pid, Signal::Continue),
+            CommandKind::SetInterval => match command.arguments.get("interval").map(|interval| interval.parse::<f64>()) {
+                Some(Ok(interval)) if interval > 0.0 => {
+                    self.interval = Some(interval);
+                    Ok(CommandEffect::SetInterval(Duration::from_secs_f64(interval)))
+                },
+                _ => Err((CommandOutcome::Rejected, "Interval must be a positive number of seconds".to_string())),
+            },
+            CommandKind::StopAfterDrain => {
+                self.stop_after_drain = true;
+                Ok(CommandEffect::None)
+            },
+            CommandKind::Unknown => Err((CommandOutcome::Rejected, "Unknown command".to_string())),
+        };
+
+        match result {
+            Ok(effect) => {
+                self.acknowledge(&command, CommandOutcome::Applied, None);
+                effect
+            },
+            Err((outcome, message)) => {
+                error!("Command {} not applied: {}", command.id, message);
+                self.acknowledge(&command, outcome, Some(message));
+                CommandEffect::None
+            }
+        }
+    }
+
+    /// Keep commands for the monitoring loop to apply once it starts
+    pub fn queue(&mut self, commands: Vec<BrainCommand>) {
+        self.queued.extend(commands);
+    }
+
+    pub fn take_queued(&mut self) -> Vec<BrainCommand> {
+        std::mem::take(&mut self.queued)
+    }
+
+    /// Acknowledgements to send with the next zap or exit
+    pub fn take_acks(&mut self) -> Option<Vec<CommandAck>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(std::mem::take(&mut self.pending))
+    }
+
+    fn acknowledge(&mut self, command: &BrainCommand, outcome: CommandOutcome, message: Option<String>) {
+        // plain text commands from older brains have no id to acknowledge
+        if command.id.is_empty() {
+            return;
+        }
+
+        if outcome != CommandOutcome::Duplicate {
+            if self.applied.len() >= APPLIED_HISTORY {
+                self.applied.pop_front();
+            }
+            self.applied.push_back(command.id.clone());
+        }
+
+        self.pending.push(CommandAck {
+            id: command.id.clone(),
+            outcome,
+            message,
+            time: Utc::now().timestamp_millis() as u64,
+        });
+    }
+}
+
+fn send_signal(child_pid: u32, signal: Signal) -> Result<CommandEffect, (CommandOutcome, String)> {
+    let sys = process_system(child_pid);
+    match sys.process(Pid::from_u32(child_pid)).map(|process| process.kill_with(signal)) {
+        Some(Some(true)) => Ok(CommandEffect::None),
+        Some(Some(false)) => Err((CommandOutcome::Failed, format!("Failed to send {:?} to process {}", signal, child_pid))),
+        Some(None) => Err((CommandOutcome::Rejected, format!("{:?} is not supported on this platform", signal))),
+        None => Err((CommandOutcome::Failed, format!("Process {} not found", child_pid))),
+    }
+}
+
+/// Parse a signal name such as `HUP`, `SIGHUP` or `sigusr1`
+pub fn parse_signal(name: &str) -> Option<Signal> {
+    let name = name.trim().to_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+
+    Some(match name {
+        "HUP" => Signal::Hangup,
+        "INT" => Signal::Interrupt,
+        "QUIT" => Signal::Quit,
+        "ABRT" => Signal::Abort,
+        "KILL" => Signal::Kill,
+        "USR1" => Signal::User1,
+        "USR2" => Signal::User2,
+        "PIPE" => Signal::Pipe,
+        "ALRM" => Signal::Alarm,
+        "TERM" => Signal::Term,
+        "CONT" => Signal::Continue,
+        "STOP" => Signal::Stop,
+        "TSTP" => Signal::TSTP,
+        "WINCH" => Signal::Winch,
+        _ => return None,
+    })
+}
+
+/// Persistent long-poll connection delivering commands as soon as the brain has them.
+///
+/// Commands still arrive on telemetry responses too, redeliveries are recognised by id.
+/// The connection is closed when the channel is dropped at the end of a run.
+pub struct CommandChannel {
+    handle: JoinHandle<()>,
+}
+
+impl CommandChannel {
+    pub fn start(client: &TelemetryClient, url: &str, uuid: &str, args: &Args, sender: mpsc::Sender<BrainCommand>) -> Option<Self> {
+        if !args.push_commands || args.prevent_telemetry || uuid.is_empty() {
+            return None;
+        }
+
+        let client = client.clone();
+        let uuid = uuid.to_string();
+        let url = url.to_string();
+
+        let handle = tokio::spawn(async move {
+            let mut backoff = Duration::from_secs(1);
+            loop {
+                match client.poll_command(&uuid, POLL_WAIT, Some(&url)).await {
+                    Ok(command) => {
+                        backoff = Duration::from_secs(1);
+                        if let Some(command) = command {
+                            if sender.send(command).await.is_err() {
+                                return;
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        debug!("Command channel failed, reconnecting in {:?}: {}", backoff, e);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_POLL_BACKOFF);
+                    }
+                }
+            }
+        });
+
+        Some(CommandChannel { handle })
+    }
+}
+
+impl Drop for CommandChannel {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
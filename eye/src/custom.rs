@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use log::{debug, error};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use crate::registry;
+use crate::types::{Args, CustomMetric};
+
+/// Values kept per histogram between zaps, older values are dropped past this
+const MAX_HISTOGRAM_VALUES: usize = 10_000;
+
+pub const STATSD_HOST_ENV: &str = "STATSD_HOST";
+pub const STATSD_PORT_ENV: &str = "STATSD_PORT";
+pub const METRICS_SOCKET_ENV: &str = "BB_EYE_METRICS_SOCKET";
+
+#[derive(Default)]
+struct Aggregator {
+    gauges: HashMap<String, f64>,
+    counters: HashMap<String, f64>,
+    histograms: HashMap<String, Vec<f64>>,
+}
+
+/// Custom metrics reported by the wrapped process in StatsD line format.
+///
+/// Gauges keep their last value across zaps, counters and histograms are
+/// aggregated over the time between two zaps.
+#[derive(Clone)]
+pub struct CustomMetrics {
+    aggregator: Arc<Mutex<Aggregator>>,
+    prefix: Option<String>,
+    env: Vec<(String, String)>,
+    /// Stopped by `stop`, or when the last clone is dropped
+    listeners: Arc<Listeners>,
+}
+
+impl CustomMetrics {
+    /// Start the listeners enabled in the args, they live for the whole session so the
+    /// command sees the same addresses after a restart.
+    pub async fn start(args: &Args, session_id: &str) -> Option<Self> {
+        if args.statsd_port.is_none() && !args.metrics_socket && args.metrics_prefix.is_none() {
+            return None;
+        }
+
+        let aggregator = Arc::new(Mutex::new(Aggregator::default()));
+        let mut env = Vec::new();
+        let mut listeners = Listeners::default();
+
+        if let Some(port) = args.statsd_port {
+            match UdpSocket::bind(("127.0.0.1", port)).await {
+                Ok(socket) => {
+                    let port = socket.local_addr().map(|addr| addr.port()).unwrap_or(port);
+                    debug!("Listening for StatsD metrics on 127.0.0.1:{}", port);
+                    env.push((STATSD_HOST_ENV.to_string(), "127.0.0.1".to_string()));
+                    env.push((STATSD_PORT_ENV.to_string(), port.to_string()));
+
+                    listeners.tasks.get_mut().unwrap().push(tokio::spawn(receive_udp(aggregator.clone(), socket)));
+                },
+                Err(e) => error!("Failed to listen for StatsD metrics on port {}: {}", port, e),
+            }
+        }
+
+        if args.metrics_socket {
+            let path = registry::runtime_dir(args).join(format!("metrics-{}-{}.sock", std::process::id(), session_id));
+            if let Some(accept) = listen_unix(&path, aggregator.clone()) {
+                env.push((METRICS_SOCKET_ENV.to_string(), path.to_string_lossy().to_string()));
+                listeners.tasks.get_mut().unwrap().push(accept);
+                listeners.socket = Some(path);
+            }
+        }
+
+        Some(CustomMetrics {
+            aggregator,
+            prefix: args.metrics_prefix.clone(),
+            env,
+            listeners: Arc::new(listeners),
+        })
+    }
+
+    /// Stop listening and wait until the port and the socket are released, for a supervisor to return without leaving them behind
+    pub async fn stop(&self) {
+        let tasks = std::mem::take(&mut *self.listeners.tasks.lock().unwrap());
+        for task in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+        if let Some(socket) = &self.listeners.socket {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+
+    /// Environment variables telling the command where to send its metrics
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
+    /// Record a stdout line if it carries the metrics prefix, returns true if it was consumed
+    pub fn record_prefixed(&self, line: &str) -> bool {
+        match self.prefix.as_ref().and_then(|prefix| line.strip_prefix(prefix.as_str())) {
+            Some(metrics) => {
+                self.record(metrics);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Record one or more newline separated StatsD lines
+    pub fn record(&self, lines: &str) {
+        record(&self.aggregator, lines);
+    }
+
+    /// Take the metrics for the next zap, `None` if nothing has been reported yet
+    pub fn take(&self) -> Option<HashMap<String, CustomMetric>> {
+        let mut aggregator = self.aggregator.lock().unwrap();
+        let mut metrics = HashMap::new();
+
+        for (name, value) in aggregator.gauges.iter() {
+            metrics.insert(name.clone(), CustomMetric::Gauge { value: *value });
+        }
+        for (name, value) in aggregator.counters.drain() {
+            metrics.insert(name, CustomMetric::Counter { value });
+        }
+        for (name, mut values) in aggregator.histograms.drain() {
+            if values.is_empty() {
+                continue;
+            }
+            values.sort_by(f64::total_cmp);
+            let percentile = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+
+            metrics.insert(name, CustomMetric::Histogram {
+                count: values.len() as u64,
+                min: values[0],
+                max: values[values.len() - 1],
+                mean: values.iter().sum::<f64>() / values.len() as f64,
+                p50: percentile(0.5),
+                p95: percentile(0.95),
+            });
+        }
+
+        if metrics.is_empty() { None } else { Some(metrics) }
+    }
+}
+
+/// Accept metrics connections on a socket in the private runtime directory, returns the accepting task
+#[cfg(unix)]
+fn listen_unix(path: &Path, aggregator: Arc<Mutex<Aggregator>>) -> Option<JoinHandle<()>> {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::UnixListener;
+
+    if let Err(e) = registry::create_private_dir(path.parent()?) {
+        error!("Failed to create the directory of the metrics socket {}: {}", path.display(), e);
+        return None;
+    }
+    // the name is unique, a file in its place belongs to someone else and is left alone
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen for metrics on {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    debug!("Listening for metrics on {}", path.display());
+    // the tasks only hold the aggregator, so the socket goes away with the last handle
+    Some(tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Failed to accept metrics connection: {}", e);
+                    return;
+                }
+            };
+
+            let aggregator = aggregator.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stream).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    record(&aggregator, &line);
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_path: &Path, _aggregator: Arc<Mutex<Aggregator>>) -> Option<JoinHandle<()>> {
+    error!("Metrics sockets are only supported on Unix");
+    None
+}
+
+fn record(aggregator: &Mutex<Aggregator>, lines: &str) {
+    let mut aggregator = aggregator.lock().unwrap();
+    for line in lines.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if !aggregator.record(line) {
+            debug!("Ignoring malformed custom metric: {}", line);
+        }
+    }
+}
+
+async fn receive_udp(aggregator: Arc<Mutex<Aggregator>>, socket: UdpSocket) {
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        match socket.recv(&mut buffer).await {
+            Ok(length) => record(&aggregator, &String::from_utf8_lossy(&buffer[..length])),
+            Err(e) => {
+                error!("Failed to receive StatsD metrics: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+impl Aggregator {
+    /// Parse `name:value|type[|@rate]`, returns false if the line is malformed
+    fn record(&mut self, line: &str) -> bool {
+        let Some((name, rest)) = line.split_once(':') else {
+            return false;
+        };
+        let mut fields = rest.split('|');
+        let raw_value = fields.next().unwrap_or("");
+        let Ok(value) = raw_value.parse::<f64>() else {
+            return false;
+        };
+        let kind = fields.next().unwrap_or("");
+        let rate = fields
+            .find_map(|field| field.strip_prefix('@'))
+            .and_then(|rate| rate.parse::<f64>().ok())
+            .filter(|rate| *rate > 0.0)
+            .unwrap_or(1.0);
+
+        match kind {
+            "g" => {
+                // a signed gauge value adjusts the current value
+                let gauge = self.gauges.entry(name.to_string()).or_insert(0.0);
+                if raw_value.starts_with('+') || raw_value.starts_with('-') {
+                    *gauge += value;
+                } else {
+                    *gauge = value;
+                }
+            },
+            "c" => *self.counters.entry(name.to_string()).or_insert(0.0) += value / rate,
+            "h" | "ms" | "d" => {
+                let values = self.histograms.entry(name.to_string()).or_default();
+                if values.len() >= MAX_HISTOGRAM_VALUES {
+                    values.remove(0);
+                }
+                values.push(value);
+            },
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+/// Stops receiving metrics and removes the socket once the last handle is gone
+#[derive(Default)]
+struct Listeners {
+    socket: Option<PathBuf>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Drop for Listeners {
+    fn drop(&mut self) {
+        for task in self.tasks.get_mut().unwrap().iter() {
+            task.abort();
+        }
+        if let Some(socket) = &self.socket {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
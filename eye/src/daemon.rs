@@ -0,0 +1,206 @@
+use std::fs::{self, File, TryLockError};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{error, info};
+use ulid::Ulid;
+use crate::registry;
+use crate::types::Args;
+
+/// Set for the detached eye, holding the path of its output file
+pub const DETACHED_ENV: &str = "BB_EYE_DETACHED";
+
+/// How long a detached eye waits for the foreground eye to hand over the pidfile lock
+const HANDOVER_WAIT: Duration = Duration::from_secs(5);
+
+/// True if this process is the background copy started by `--detach`
+pub fn is_detached() -> bool {
+    std::env::var_os(DETACHED_ENV).is_some()
+}
+
+/// Output file of a detached eye
+pub fn log_path() -> Option<String> {
+    std::env::var(DETACHED_ENV).ok()
+}
+
+/// Start this eye again in a new session with its output in a file, returns the exit code of the foreground eye.
+///
+/// The pid of the background eye is printed and written to `--pidfile` before returning,
+/// so scripts can use either right away.
+pub fn detach(args: &Args) -> i32 {
+    // held until the pid of the background eye is written, which then claims it for itself
+    let Some(mut pidfile) = lock_pidfile(args, Duration::ZERO) else {
+        return 1;
+    };
+
+    let log = match &args.daemon_log {
+        Some(log) => log.clone(),
+        None => {
+            let dir = registry::runtime_dir(args);
+            if let Err(e) = fs::create_dir_all(&dir) {
+                error!("Failed to create runtime directory {}: {}", dir.display(), e);
+                return 1;
+            }
+            dir.join(format!("{}.log", Ulid::new())).to_string_lossy().to_string()
+        },
+    };
+
+    let output = match File::options().append(true).create(true).open(&log).and_then(|file| Ok((file.try_clone()?, file))) {
+        Ok(output) => output,
+        Err(e) => {
+            error!("Failed to open daemon log {}: {}", log, e);
+            return 1;
+        }
+    };
+
+    let executable = match std::env::current_exe() {
+        Ok(executable) => executable,
+        Err(e) => {
+            error!("Failed to find the bb_eye executable: {}", e);
+            return 1;
+        }
+    };
+
+    let mut command = Command::new(executable);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(DETACHED_ENV, &log)
+        .stdin(Stdio::null())
+        .stdout(output.0)
+        .stderr(output.1);
+
+    // a new session without a terminal, so closing the shell does not hang the eye up
+    #[cfg(unix)]
+    unsafe {
+        use std::os::unix::process::CommandExt;
+        command.pre_exec(|| {
+            if libc::setsid() < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    match command.spawn() {
+        Ok(child) => {
+            pidfile.write(child.id());
+            info!("Detached eye {} writing to {}", child.id(), log);
+            println!("{}", child.id());
+            0
+        },
+        Err(e) => {
+            error!("Failed to detach: {}", e);
+            pidfile.release();
+            1
+        }
+    }
+}
+
+/// `--pidfile` locked by this eye, the lock is held until the pidfile is released or the eye exits
+pub struct Pidfile {
+    locked: Option<(String, File)>,
+}
+
+impl Pidfile {
+    fn write(&mut self, pid: u32) {
+        if let Some((path, file)) = &mut self.locked {
+            // written over the old pid and cut after, so readers never find the file empty
+            let line = format!("{}\n", pid);
+            let result = file.seek(SeekFrom::Start(0)).and_then(|_| file.write_all(line.as_bytes())).and_then(|_| file.set_len(line.len() as u64));
+            if let Err(e) = result {
+                error!("Failed to write pidfile {}: {}", path, e);
+            }
+        }
+    }
+
+    /// Remove the pidfile while it is still locked, so no other eye claims it in between
+    pub fn release(self) {
+        if let Some((path, _file)) = self.locked {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Lock `--pidfile` and write the pid of this eye to it, `None` if another eye holds it
+pub fn claim_pidfile(args: &Args) -> Option<Pidfile> {
+    // the foreground eye holds the lock until it has written the pid of the detached one
+    let wait = if is_detached() { HANDOVER_WAIT } else { Duration::ZERO };
+    let mut pidfile = lock_pidfile(args, wait)?;
+    pidfile.write(std::process::id());
+    Some(pidfile)
+}
+
+/// Lock `--pidfile`, `None` if another eye holds the lock or the pid in it is still running
+fn lock_pidfile(args: &Args, wait: Duration) -> Option<Pidfile> {
+    let Some(path) = &args.pidfile else {
+        return Some(Pidfile { locked: None });
+    };
+
+    let file = match lock(path, wait) {
+        Ok(Some(file)) => file,
+        Ok(None) => {
+            error!("Another eye holds the lock on pidfile {}", path);
+            return None;
+        },
+        Err(e) => {
+            error!("Failed to lock pidfile {}: {}", path, e);
+            return Some(Pidfile { locked: None });
+        },
+    };
+
+    // only replaced under the lock, and a detached eye keeps its pid until it takes the lock itself
+    if let Some(pid) = read_pid(path).filter(|pid| *pid != std::process::id() && is_running(*pid)) {
+        error!("An eye with pid {} is already running for pidfile {}", pid, path);
+        return None;
+    }
+
+    Some(Pidfile { locked: Some((path.clone(), file)) })
+}
+
+/// Open and lock the file at the path, `None` if it is still locked after `wait`
+fn lock(path: &str, wait: Duration) -> io::Result<Option<File>> {
+    let started = Instant::now();
+    loop {
+        let file = File::options().read(true).write(true).create(true).truncate(false).open(path)?;
+        match file.try_lock() {
+            Ok(()) if is_current(&file, path) => return Ok(Some(file)),
+            // removed by the eye that held it in the meantime, the lock is taken on the new file
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) if started.elapsed() < wait => thread::sleep(Duration::from_millis(20)),
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+    }
+}
+
+/// Whether the path still names the open file
+#[cfg(unix)]
+fn is_current(file: &File, path: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(open), Ok(current)) => open.dev() == current.dev() && open.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &str) -> bool {
+    true
+}
+
+fn read_pid(path: &str) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    // signal 0 only checks whether the process exists, EPERM means it belongs to another user
+    let exists = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+pub fn is_running(pid: u32) -> bool {
+    crate::utils::process_system(pid).process(sysinfo::Pid::from_u32(pid)).is_some()
+}
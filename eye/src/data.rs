@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, error};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use crate::types::{Args, DataUsage};
+
+/// Parse `--data-scan-interval`, a positive number of seconds
+pub fn parse_scan_interval(seconds: &str) -> Result<f64, String> {
+    let seconds: f64 = seconds.parse().map_err(|e: std::num::ParseFloatError| e.to_string())?;
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(interval) if !interval.is_zero() => Ok(seconds),
+        _ => Err(format!("expected a positive number of seconds, got {}", seconds)),
+    }
+}
+
+/// Tracks the size of the data folders and files on a background thread.
+///
+/// Every path is fully scanned on its own cadence, independent of the telemetry
+/// interval. In watch mode file system events adjust the totals between scans,
+/// so the scan only has to correct drift.
+pub struct DataTracker {
+    usage: Arc<Mutex<Vec<DataUsage>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl DataTracker {
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.data_folder.is_empty() {
+            return None;
+        }
+
+        let roots: Vec<Root> = args.data_folder.iter().map(|path| Root::new(PathBuf::from(path), args.data_watch)).collect();
+        let usage = Arc::new(Mutex::new(roots.iter().map(Root::usage).collect()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let scan_interval = Duration::from_secs_f64(args.data_scan_interval);
+        let watch = args.data_watch;
+        let thread_usage = usage.clone();
+        let thread_stop = stop.clone();
+        thread::spawn(move || track(roots, scan_interval, watch, thread_usage, thread_stop));
+
+        Some(DataTracker { usage, stop })
+    }
+
+    /// The most recent usage of every tracked path.
+    pub fn usage(&self) -> Vec<DataUsage> {
+        self.usage.lock().unwrap().clone()
+    }
+}
+
+impl Drop for DataTracker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+struct Root {
+    path: PathBuf,
+    size: Option<u64>,
+    count: u64,
+    /// Per file sizes, only kept in watch mode to apply deltas
+    files: Option<HashMap<PathBuf, u64>>,
+    error: Option<String>,
+}
+
+impl Root {
+    fn new(path: PathBuf, watch: bool) -> Self {
+        Root { path, size: None, count: 0, files: if watch { Some(HashMap::new()) } else { None }, error: None }
+    }
+
+    fn usage(&self) -> DataUsage {
+        DataUsage {
+            path: self.path.to_string_lossy().to_string(),
+            size: self.size,
+            files: self.size.map(|_| self.count),
+            error: self.error.clone(),
+        }
+    }
+
+    fn scan(&mut self) {
+        let keep_files = self.files.is_some();
+        let mut files = HashMap::new();
+        let mut count = 0;
+        let mut first_error = None;
+
+        let size = walk(&self.path, &mut |path, size| {
+            count += 1;
+            if keep_files {
+                files.insert(path, size);
+            }
+        }, &mut first_error);
+
+        if let Some(e) = &first_error {
+            if self.error.as_ref() != Some(e) {
+                error!("Failed to read data path {}: {}", self.path.display(), e);
+            }
+        }
+
+        // a missing or unreadable root has no size rather than a size of zero
+        self.size = if self.path.symlink_metadata().is_ok() { Some(size) } else { None };
+        self.count = count;
+        self.error = first_error;
+        if keep_files {
+            self.files = Some(files);
+        }
+    }
+
+    /// Apply a file system event to the running totals, returns false if a full scan is needed
+    fn apply(&mut self, path: &Path) -> bool {
+        if self.files.is_none() || self.size.is_none() {
+            return false;
+        }
+
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                let mut first_error = None;
+                let mut added = Vec::new();
+                walk(path, &mut |path, size| added.push((path, size)), &mut first_error);
+                for (path, size) in added {
+                    self.update(path, Some(size));
+                }
+            },
+            Ok(metadata) => self.update(path.to_path_buf(), Some(metadata.len())),
+            Err(_) => {
+                // removed, drop the path and anything that was below it
+                let removed: Vec<PathBuf> = self.files.iter().flat_map(|files| files.keys()).filter(|file| file.starts_with(path)).cloned().collect();
+                for file in removed {
+                    self.update(file, None);
+                }
+            },
+        }
+
+        true
+    }
+
+    fn update(&mut self, path: PathBuf, new_size: Option<u64>) {
+        let (Some(files), Some(size)) = (self.files.as_mut(), self.size.as_mut()) else {
+            return;
+        };
+
+        let previous = match new_size {
+            Some(new_size) => files.insert(path, new_size),
+            None => files.remove(&path),
+        };
+        match previous {
+            Some(_) if new_size.is_none() => self.count -= 1,
+            None if new_size.is_some() => self.count += 1,
+            _ => {}
+        }
+        *size = (*size + new_size.unwrap_or(0)).saturating_sub(previous.unwrap_or(0));
+    }
+}
+
+/// Walk a file or directory tree, calling `on_file` for each file and returning the total size.
+/// Unreadable entries are skipped, the first error is kept for reporting.
+fn walk(root: &Path, on_file: &mut dyn FnMut(PathBuf, u64), first_error: &mut Option<String>) -> u64 {
+    let mut total = 0;
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(path) = pending.pop() {
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                first_error.get_or_insert_with(|| format!("{}: {}", path.display(), e));
+                continue;
+            }
+        };
+
+        if metadata.is_dir() {
+            match std::fs::read_dir(&path) {
+                Ok(entries) => pending.extend(entries.filter_map(|entry| entry.ok()).map(|entry| entry.path())),
+                Err(e) => {
+                    first_error.get_or_insert_with(|| format!("{}: {}", path.display(), e));
+                }
+            }
+        } else {
+            total += metadata.len();
+            on_file(path, metadata.len());
+        }
+    }
+
+    total
+}
+
+fn track(mut roots: Vec<Root>, scan_interval: Duration, watch: bool, usage: Arc<Mutex<Vec<DataUsage>>>, stop: Arc<AtomicBool>) {
+    let events = if watch { start_watcher(&roots) } else { None };
+    let publish = |roots: &Vec<Root>| *usage.lock().unwrap() = roots.iter().map(Root::usage).collect();
+
+    while !stop.load(Ordering::SeqCst) {
+        let started = Instant::now();
+        for root in roots.iter_mut() {
+            root.scan();
+            debug!("Scanned data path {}: {:?} bytes in {} files", root.path.display(), root.size, root.count);
+        }
+        publish(&roots);
+
+        let next_scan = started + scan_interval;
+        while !stop.load(Ordering::SeqCst) && Instant::now() < next_scan {
+            // wake at least every second to notice the tracker being dropped
+            let wait = next_scan.saturating_duration_since(Instant::now()).min(Duration::from_secs(1));
+
+            let Some((_watcher, receiver)) = &events else {
+                thread::sleep(wait);
+                continue;
+            };
+
+            match receiver.recv_timeout(wait) {
+                Ok(Ok(event)) => {
+                    if apply_event(&mut roots, &event) {
+                        publish(&roots);
+                    } else {
+                        break;
+                    }
+                },
+                Ok(Err(e)) => {
+                    debug!("Data watch error, rescanning: {}", e);
+                    break;
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(wait),
+            }
+        }
+    }
+}
+
+type Events = (RecommendedWatcher, Receiver<notify::Result<Event>>);
+
+fn start_watcher(roots: &[Root]) -> Option<Events> {
+    let (sender, receiver) = channel();
+    let mut watcher = match notify::recommended_watcher(sender) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Failed to watch data paths, falling back to scanning: {}", e);
+            return None;
+        }
+    };
+
+    for root in roots {
+        if let Err(e) = watcher.watch(&root.path, RecursiveMode::Recursive) {
+            error!("Failed to watch data path {}: {}", root.path.display(), e);
+        }
+    }
+
+    Some((watcher, receiver))
+}
+
+/// Returns false if the event could not be applied incrementally
+fn apply_event(roots: &mut [Root], event: &Event) -> bool {
+    if matches!(event.kind, EventKind::Access(_)) {
+        return true;
+    }
+    if event.need_rescan() {
+        return false;
+    }
+
+    for path in &event.paths {
+        if let Some(root) = roots.iter_mut().find(|root| path.starts_with(&root.path)) {
+            if !root.apply(path) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
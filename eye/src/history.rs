@@ -0,0 +1,220 @@
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use log::{debug, error};
+use rusqlite::{params, Connection};
+use crate::types::{Args, Exit, Introduction, MessageBuffer, Zap};
+
+/// How often old history is removed and downsampled while the eye runs
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Zaps older than the downsampling age are thinned to one per run and minute
+const DOWNSAMPLE_BUCKET_MS: i64 = 60_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        run_id TEXT PRIMARY KEY,
+        session_id TEXT NOT NULL,
+        restart_index INTEGER NOT NULL,
+        uuid TEXT,
+        name TEXT NOT NULL,
+        args TEXT NOT NULL,
+        display_name TEXT,
+        pid INTEGER NOT NULL,
+        started INTEGER NOT NULL,
+        ended INTEGER,
+        exit_code INTEGER,
+        introduction TEXT NOT NULL,
+        summary TEXT
+    );
+    CREATE TABLE IF NOT EXISTS zaps (
+        run_id TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        memory REAL NOT NULL,
+        cpu REAL NOT NULL,
+        disk INTEGER,
+        zap TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS zaps_run_time ON zaps (run_id, time);
+    CREATE TABLE IF NOT EXISTS messages (
+        run_id TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        error INTEGER NOT NULL,
+        message TEXT NOT NULL,
+        source TEXT
+    );
+    CREATE INDEX IF NOT EXISTS messages_time ON messages (time);
+";
+
+enum Record {
+    Introduction(Box<Introduction>),
+    Zap(Box<Zap>, Vec<MessageBuffer>),
+    Exit(Box<Exit>),
+}
+
+/// The database given with `--history`, written on a background thread so a slow disk never delays sampling
+pub struct History {
+    sender: Sender<Record>,
+    handle: JoinHandle<()>,
+}
+
+/// Age limits of the history, in milliseconds
+#[derive(Clone, Copy)]
+struct Retention {
+    keep: i64,
+    downsample_after: i64,
+}
+
+impl History {
+    /// Open the database and start recording into it, `None` without `--history` or if it cannot be opened
+    pub fn open(args: &Args) -> Option<Self> {
+        let path = args.history.as_ref()?;
+        let connection = match connect(path) {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("Failed to open history database {}: {}", path, e);
+                return None;
+            }
+        };
+
+        let retention = Retention {
+            keep: (args.history_retention * 86_400_000.0) as i64,
+            downsample_after: (args.history_downsample_after * 3_600_000.0) as i64,
+        };
+
+        let (sender, receiver) = channel();
+        let handle = thread::spawn(move || {
+            maintain(&connection, retention);
+            let mut last_maintenance = Instant::now();
+
+            loop {
+                match receiver.recv_timeout(MAINTENANCE_INTERVAL) {
+                    Ok(record) => {
+                        if let Err(e) = write(&connection, record) {
+                            error!("Failed to write history: {}", e);
+                        }
+                    },
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+
+                if last_maintenance.elapsed() >= MAINTENANCE_INTERVAL {
+                    maintain(&connection, retention);
+                    last_maintenance = Instant::now();
+                }
+            }
+        });
+
+        Some(History { sender, handle })
+    }
+
+    /// Wait for every record to be written
+    pub fn close(self) {
+        drop(self.sender);
+        let _ = self.handle.join();
+    }
+
+    pub fn record_introduction(&self, introduction: &Introduction) {
+        let _ = self.sender.send(Record::Introduction(Box::new(introduction.clone())));
+    }
+
+    /// Record a zap with the lines logged since the previous one, kept even when remote logs are off
+    pub fn record_zap(&self, zap: &Zap, messages: &[MessageBuffer]) {
+        let _ = self.sender.send(Record::Zap(Box::new(zap.clone()), messages.to_vec()));
+    }
+
+    pub fn record_exit(&self, exit: &Exit) {
+        let _ = self.sender.send(Record::Exit(Box::new(exit.clone())));
+    }
+}
+
+/// Open the history database, creating the tables if needed
+pub fn connect(path: &str) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(path)?;
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", "NORMAL")?;
+    connection.execute_batch(SCHEMA)?;
+    // databases from before tailed files have no source for their lines
+    if !has_column(&connection, "messages", "source")? {
+        connection.execute_batch("ALTER TABLE messages ADD COLUMN source TEXT")?;
+    }
+    Ok(connection)
+}
+
+/// Whether a table of the database has the column, for databases written by older eyes
+pub fn has_column(connection: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut statement = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = statement.query_map([], |row| row.get::<_, String>(1))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(columns.iter().any(|name| name == column))
+}
+
+fn write(connection: &Connection, record: Record) -> Result<(), Box<dyn std::error::Error>> {
+    match record {
+        Record::Introduction(introduction) => {
+            connection.execute(
+                "INSERT OR REPLACE INTO runs (run_id, session_id, restart_index, name, args, display_name, pid, started, introduction)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    introduction.run.run_id,
+                    introduction.run.session_id,
+                    introduction.run.restart_index,
+                    introduction.name,
+                    introduction.args,
+                    introduction.display_name,
+                    introduction.pid,
+                    introduction.time as i64,
+                    serde_json::to_string(&introduction)?,
+                ],
+            )?;
+        },
+        Record::Zap(mut zap, messages) => {
+            zap.messages = None;
+            let transaction = connection.unchecked_transaction()?;
+            for message in messages {
+                transaction.execute(
+                    "INSERT INTO messages (run_id, time, error, message, source) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![zap.run.run_id, message.timestamp as i64, message.error, message.message, message.source],
+                )?;
+            }
+            transaction.execute(
+                "INSERT INTO zaps (run_id, time, memory, cpu, disk, zap) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![zap.run.run_id, zap.time as i64, zap.memory, zap.cpu, zap.disk.map(|disk| disk as i64), serde_json::to_string(&zap)?],
+            )?;
+            transaction.commit()?;
+        },
+        Record::Exit(exit) => {
+            let summary = exit.summary.as_ref().map(serde_json::to_string).transpose()?;
+            connection.execute(
+                "UPDATE runs SET uuid = ?2, ended = ?3, exit_code = ?4, summary = ?5 WHERE run_id = ?1",
+                params![exit.run.run_id, exit.uuid, exit.time as i64, exit.exit_code, summary],
+            )?;
+        },
+    }
+
+    Ok(())
+}
+
+/// Drop history past the retention and thin out old zaps
+fn maintain(connection: &Connection, retention: Retention) {
+    let now = Utc::now().timestamp_millis();
+    match prune(connection, now - retention.keep, now - retention.downsample_after) {
+        Ok(removed) => debug!("History maintenance removed {} rows", removed),
+        Err(e) => error!("Failed to maintain history: {}", e),
+    }
+}
+
+fn prune(connection: &Connection, expired: i64, downsampled: i64) -> rusqlite::Result<usize> {
+    let transaction = connection.unchecked_transaction()?;
+    let mut removed = transaction.execute("DELETE FROM zaps WHERE time < ?1", [expired])?;
+    removed += transaction.execute("DELETE FROM messages WHERE time < ?1", [expired])?;
+    removed += transaction.execute("DELETE FROM runs WHERE COALESCE(ended, started) < ?1", [expired])?;
+    removed += transaction.execute(
+        "DELETE FROM zaps WHERE time < ?1 AND rowid NOT IN (
+            SELECT MIN(rowid) FROM zaps WHERE time < ?1 GROUP BY run_id, time / ?2
+        )",
+        [downsampled, DOWNSAMPLE_BUCKET_MS],
+    )?;
+    transaction.commit()?;
+    Ok(removed)
+}
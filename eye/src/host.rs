@@ -0,0 +1,85 @@
+use std::time::Instant;
+use sysinfo::{System, Disks, Networks};
+use crate::types::{Args, HostMetric, HostMetrics, LoadAverage, DiskSpace, NetworkThroughput};
+
+/// Samples host-level metrics for the zap.
+///
+/// Only the enabled parts of the host are refreshed, so an unused metric costs nothing.
+pub struct HostMonitor {
+    memory: bool,
+    load: bool,
+    cpu: bool,
+    uptime: bool,
+    disks: Option<Disks>,
+    networks: Option<Networks>,
+    last_network_refresh: Instant,
+}
+
+impl HostMonitor {
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.host_metrics.is_empty() {
+            return None;
+        }
+
+        let enabled = |metric: HostMetric| args.host_metrics.contains(&HostMetric::All) || args.host_metrics.contains(&metric);
+
+        Some(HostMonitor {
+            memory: enabled(HostMetric::Memory),
+            load: enabled(HostMetric::Load),
+            cpu: enabled(HostMetric::Cpu),
+            uptime: enabled(HostMetric::Uptime),
+            disks: if enabled(HostMetric::Disks) { Some(Disks::new_with_refreshed_list()) } else { None },
+            networks: if enabled(HostMetric::Network) { Some(Networks::new_with_refreshed_list()) } else { None },
+            last_network_refresh: Instant::now(),
+        })
+    }
+
+    pub fn sample(&mut self, sys: &mut System) -> HostMetrics {
+        let mut host = HostMetrics::default();
+
+        if self.memory {
+            sys.refresh_memory();
+            host.total_memory = Some(sys.total_memory());
+            host.used_memory = Some(sys.used_memory());
+            host.total_swap = Some(sys.total_swap());
+            host.used_swap = Some(sys.used_swap());
+        }
+
+        if self.load {
+            let load = System::load_average();
+            host.load_average = Some(LoadAverage { one: load.one, five: load.five, fifteen: load.fifteen });
+        }
+
+        if self.cpu {
+            sys.refresh_cpu_usage();
+            host.cpus = Some(sys.cpus().iter().map(|cpu| cpu.cpu_usage() as f64).collect());
+        }
+
+        if self.uptime {
+            host.uptime = Some(System::uptime());
+        }
+
+        if let Some(disks) = &mut self.disks {
+            disks.refresh(true);
+            host.disks = Some(disks.list().iter().map(|disk| DiskSpace {
+                mount_point: disk.mount_point().to_string_lossy().to_string(),
+                total: disk.total_space(),
+                available: disk.available_space(),
+            }).collect());
+        }
+
+        if let Some(networks) = &mut self.networks {
+            networks.refresh(true);
+            let elapsed = self.last_network_refresh.elapsed().as_secs_f64().max(f64::EPSILON);
+            self.last_network_refresh = Instant::now();
+
+            host.networks = Some(networks.list().iter().map(|(interface, data)| NetworkThroughput {
+                interface: interface.clone(),
+                received: data.received() as f64 / elapsed,
+                transmitted: data.transmitted() as f64 / elapsed,
+            }).collect());
+        }
+
+        host
+    }
+}
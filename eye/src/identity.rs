@@ -0,0 +1,170 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use log::debug;
+use sha2::{Digest, Sha256};
+use sysinfo::{Networks, System};
+use crate::labels;
+use crate::types::{Args, EndpointMode, Identity, MetricsLevel};
+
+/// Kubernetes downward API variables, as set by the usual pod spec
+const POD_NAME_ENV: &str = "POD_NAME";
+const POD_NAMESPACE_ENV: &str = "POD_NAMESPACE";
+
+/// Gather the identity of the host, the container and the executable for the introduction.
+/// Anything that cannot be determined is left out.
+pub async fn gather(args: &Args, root_proc: &str) -> Identity {
+    let (container_id, container_runtime) = detect_container();
+    let executable = resolve_executable(root_proc);
+    // hashing a large binary would hold up the runtime, and the command is already running
+    let executable_sha256 = match executable.clone() {
+        Some(path) => tokio::task::spawn_blocking(move || hash_file(&path)).await.ok().flatten(),
+        None => None,
+    };
+
+    Identity {
+        machine_id: read_id(&["/etc/machine-id", "/var/lib/dbus/machine-id"]),
+        boot_id: read_id(&["/proc/sys/kernel/random/boot_id"]),
+        os_version: System::long_os_version(),
+        kernel_version: System::kernel_version(),
+        arch: Some(System::cpu_arch()).filter(|arch| !arch.is_empty()),
+        ip_addresses: ip_addresses(),
+        container_id,
+        container_runtime,
+        kubernetes_pod: std::env::var(POD_NAME_ENV).ok(),
+        kubernetes_namespace: std::env::var(POD_NAMESPACE_ENV).ok(),
+        executable_sha256,
+        executable: executable.map(|path| path.to_string_lossy().to_string()),
+        eye_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        features: Some(features(args)),
+        labels: labels::from_args(args),
+    }
+}
+
+fn read_id(paths: &[&str]) -> Option<String> {
+    paths.iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|id| id.trim().to_string())
+        .find(|id| !id.is_empty())
+}
+
+/// Addresses of every interface, without loopback and link-local addresses
+fn ip_addresses() -> Option<Vec<String>> {
+    let networks = Networks::new_with_refreshed_list();
+    let addresses: BTreeSet<String> = networks.values()
+        .flat_map(|network| network.ip_networks())
+        .map(|network| network.addr)
+        .filter(|addr| !addr.is_loopback() && !addr.is_unspecified() && !is_link_local(addr))
+        .map(|addr| addr.to_string())
+        .collect();
+
+    if addresses.is_empty() { None } else { Some(addresses.into_iter().collect()) }
+}
+
+fn is_link_local(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_link_local(),
+        IpAddr::V6(addr) => (addr.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// Container id and runtime from the cgroups and mounts of the eye
+fn detect_container() -> (Option<String>, Option<String>) {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    // with cgroup namespaces the id only shows in the files the runtime bind mounts
+    let mounts = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    let runtime_mounts = mounts.lines().filter(|line| {
+        let mount_point = line.split(' ').nth(4).unwrap_or("");
+        matches!(mount_point, "/etc/hostname" | "/etc/hosts" | "/etc/resolv.conf")
+    });
+
+    for line in cgroups.lines().chain(runtime_mounts) {
+        if let Some(id) = container_id(line) {
+            return (Some(id), container_runtime(line).map(str::to_string));
+        }
+    }
+
+    let runtime = if Path::new("/.dockerenv").exists() {
+        Some("docker".to_string())
+    } else if Path::new("/run/.containerenv").exists() {
+        Some("podman".to_string())
+    } else {
+        None
+    };
+
+    (None, runtime)
+}
+
+fn container_runtime(line: &str) -> Option<&'static str> {
+    // order matters, kubernetes paths also name the runtime below them
+    if line.contains("cri-containerd") || line.contains("/containerd") {
+        Some("containerd")
+    } else if line.contains("crio-") {
+        Some("cri-o")
+    } else if line.contains("libpod") {
+        Some("podman")
+    } else if line.contains("docker") {
+        Some("docker")
+    } else if line.contains("kubepods") {
+        Some("kubernetes")
+    } else if line.contains("/lxc/") {
+        Some("lxc")
+    } else {
+        None
+    }
+}
+
+/// Container ids are 64 hex characters in every runtime above
+fn container_id(line: &str) -> Option<String> {
+    line.split(['/', '-', '.', ':', ' '])
+        .find(|part| part.len() == 64 && part.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(str::to_string)
+}
+
+/// Find the command on the PATH the way the spawn does
+fn resolve_executable(root_proc: &str) -> Option<PathBuf> {
+    let path = Path::new(root_proc);
+    if path.components().count() > 1 {
+        return path.canonicalize().ok();
+    }
+
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(root_proc))
+        .find(|candidate| candidate.is_file())
+        .and_then(|candidate| candidate.canonicalize().ok())
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    if let Err(e) = std::io::copy(&mut file, &mut hasher) {
+        debug!("Failed to hash {}: {}", path.display(), e);
+        return None;
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+/// Optional behaviour enabled for this eye
+fn features(args: &Args) -> Vec<String> {
+    let enabled = [
+        (args.restart, "restart"),
+        (args.pid.is_some() || args.match_process.is_some(), "attach"),
+        (args.reattach, "reattach"),
+        (!args.no_metrics, "metrics"),
+        (args.metrics_level == MetricsLevel::Extended, "extended-metrics"),
+        (!args.host_metrics.is_empty(), "host-metrics"),
+        (!args.data_folder.is_empty(), "data"),
+        (args.data_watch, "data-watch"),
+        (args.statsd_port.is_some(), "statsd"),
+        (args.metrics_socket, "metrics-socket"),
+        (args.metrics_prefix.is_some(), "metrics-prefix"),
+        (!args.no_remote_logs, "remote-logs"),
+        (args.push_commands, "push-commands"),
+        (args.endpoint_mode == EndpointMode::FanOut, "fan-out"),
+        (args.log_to_file.is_some(), "log-to-file"),
+        (!args.tail_file.is_empty(), "tail-file"),
+    ];
+
+    enabled.iter().filter(|(on, _)| *on).map(|(_, name)| name.to_string()).collect()
+}
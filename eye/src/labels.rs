@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use log::error;
+use crate::types::Args;
+
+/// Labels from the `--label` flags and label files, `None` if there are none.
+///
+/// Files are read again for every run, so edited labels apply after a restart.
+/// Flags override labels from files.
+pub fn from_args(args: &Args) -> Option<HashMap<String, String>> {
+    let mut labels = HashMap::new();
+
+    for path in &args.label_file {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Failed to read label file {}: {}", path, e);
+                continue;
+            }
+        };
+
+        for line in content.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            match parse_label(line) {
+                Ok((key, value)) => {
+                    labels.insert(key, value);
+                },
+                Err(e) => error!("Ignoring label in {}: {}", path, e),
+            }
+        }
+    }
+
+    labels.extend(args.label.iter().cloned());
+
+    if labels.is_empty() { None } else { Some(labels) }
+}
+
+/// Parse `key=value`, expanding `${VAR}` and `${VAR:-default}` in the value from the environment
+pub fn parse_label(label: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = label.split_once('=') else {
+        return Err(format!("expected key=value, got `{}`", label));
+    };
+
+    let key = key.trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(format!("invalid label key `{}`", key));
+    }
+
+    Ok((key.to_string(), expand(value.trim())?))
+}
+
+fn expand(template: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(format!("unclosed variable in `{}`", template));
+        };
+
+        let variable = &rest[start + 2..start + end];
+        let (name, default) = match variable.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (variable, None),
+        };
+
+        match std::env::var(name) {
+            Ok(value) if !value.is_empty() => expanded.push_str(&value),
+            _ => expanded.push_str(default.unwrap_or("")),
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
@@ -0,0 +1,33 @@
+//! Process supervision and telemetry for the BigBrother brain.
+//!
+//! [`Supervisor`] runs a command and reports it the way the `bb_eye` binary does.
+//! [`TelemetryClient`] sends the wire types in [`types`] from inside another program.
+
+pub mod types;
+pub mod telemetry;
+pub mod brains;
+pub mod supervisor;
+pub mod metrics;
+pub mod history;
+pub mod query;
+pub mod record;
+pub mod schema;
+pub mod daemon;
+pub mod registry;
+mod utils;
+mod host;
+mod data;
+mod custom;
+mod commands;
+mod identity;
+mod labels;
+mod summary;
+mod circuit;
+mod sender;
+mod systemd;
+mod attach;
+mod tail;
+
+pub use supervisor::Supervisor;
+pub use telemetry::{TelemetryClient, TelemetryConfig};
+pub use types::{Endpoint, Exit, Introduction, MessageBuffer, RunIdentity, Zap};
@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+use sysinfo::{System, Process, Pid, ProcessRefreshKind, ProcessesToUpdate};
+use crate::host::HostMonitor;
+use crate::data::DataTracker;
+use crate::types::{Args, MetricsLevel, ExtendedMetrics, HostMetrics, DataUsage};
+
+/// Metrics gathered since the last zap was built.
+#[derive(Debug, Default)]
+pub struct Sample {
+    pub memory: f64,
+    pub cpu: f64,
+    pub disk: Option<u64>,
+    pub data: Option<Vec<DataUsage>>,
+    pub extended: Option<ExtendedMetrics>,
+    pub host: Option<HostMetrics>,
+}
+
+/// Samples the monitored process from a long-lived `System`.
+///
+/// Only the monitored PID is refreshed, with just the process details the enabled
+/// metrics need. CPU usage is averaged over every sample taken between two zaps,
+/// the other values are the most recent ones.
+pub struct Sampler<'a> {
+    sys: &'a mut System,
+    pid: Pid,
+    refresh_kind: ProcessRefreshKind,
+    track_metrics: bool,
+    host_monitor: Option<HostMonitor>,
+    extended_monitor: Option<ExtendedMonitor>,
+    data_tracker: Option<DataTracker>,
+    latest: Sample,
+    cpu_total: f64,
+    cpu_samples: u32,
+    stats: ProcessStats,
+}
+
+/// Statistics over every sample of a run, for the run summary.
+#[derive(Debug, Default)]
+pub struct ProcessStats {
+    /// Samples per whole CPU percent, enough for percentiles without keeping every value
+    cpu_histogram: BTreeMap<u32, u64>,
+    cpu_total: f64,
+    cpu_samples: u64,
+    pub peak_memory: Option<f64>,
+    pub peak_disk: Option<u64>,
+}
+
+impl ProcessStats {
+    pub fn average_cpu(&self) -> Option<f64> {
+        if self.cpu_samples == 0 {
+            return None;
+        }
+        Some(self.cpu_total / self.cpu_samples as f64)
+    }
+
+    pub fn cpu_percentile(&self, percentile: f64) -> Option<f64> {
+        let rank = (self.cpu_samples as f64 * percentile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (cpu, count) in &self.cpu_histogram {
+            seen += count;
+            if seen >= rank {
+                return Some(*cpu as f64);
+            }
+        }
+        None
+    }
+
+    fn record_cpu(&mut self, cpu: f64) {
+        *self.cpu_histogram.entry(cpu.round() as u32).or_insert(0) += 1;
+        self.cpu_total += cpu;
+        self.cpu_samples += 1;
+    }
+}
+
+impl<'a> Sampler<'a> {
+    pub fn new(sys: &'a mut System, pid: Pid, args: &Args) -> Self {
+        let extended_monitor = ExtendedMonitor::from_args(args);
+
+        let mut refresh_kind = ProcessRefreshKind::nothing();
+        if !args.no_metrics {
+            refresh_kind = refresh_kind.with_memory().with_cpu();
+        }
+        if extended_monitor.is_some() {
+            refresh_kind = refresh_kind.with_disk_usage();
+        }
+
+        Sampler {
+            sys,
+            pid,
+            refresh_kind,
+            track_metrics: !args.no_metrics,
+            host_monitor: HostMonitor::from_args(args),
+            extended_monitor,
+            data_tracker: DataTracker::from_args(args),
+            latest: Sample::default(),
+            cpu_total: 0.0,
+            cpu_samples: 0,
+            stats: ProcessStats::default(),
+        }
+    }
+
+    /// Refresh the monitored process, returns false once it can no longer be found.
+    pub fn sample(&mut self) -> bool {
+        self.latest.host = self.host_monitor.as_mut().map(|monitor| monitor.sample(self.sys));
+
+        self.sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[self.pid]), true, self.refresh_kind);
+        let Some(process) = self.sys.process(self.pid) else {
+            return false;
+        };
+
+        if self.track_metrics {
+            self.latest.memory = process.memory() as f64;
+            self.cpu_total += process.cpu_usage() as f64;
+            self.cpu_samples += 1;
+
+            self.stats.record_cpu(process.cpu_usage() as f64);
+            self.stats.peak_memory = Some(self.stats.peak_memory.unwrap_or(0.0).max(self.latest.memory));
+        }
+        self.latest.extended = self.extended_monitor.as_mut().map(|monitor| monitor.sample(process));
+
+        true
+    }
+
+    /// Take the metrics for the next zap and start a new CPU averaging window.
+    pub fn take(&mut self) -> Sample {
+        if self.cpu_samples > 0 {
+            self.latest.cpu = self.cpu_total / self.cpu_samples as f64;
+            self.cpu_total = 0.0;
+            self.cpu_samples = 0;
+        }
+
+        // data paths are scanned in the background, report whatever is known
+        let data = self.data_tracker.as_ref().map(DataTracker::usage);
+        let disk = data.as_ref().and_then(|data| data.iter().filter_map(|usage| usage.size).reduce(|a, b| a + b));
+        self.stats.peak_disk = self.stats.peak_disk.max(disk);
+
+        Sample {
+            memory: self.latest.memory,
+            cpu: self.latest.cpu,
+            disk,
+            data,
+            extended: self.latest.extended.clone(),
+            host: self.latest.host.clone(),
+        }
+    }
+
+    /// Statistics over every sample taken so far
+    pub fn stats(&self) -> &ProcessStats {
+        &self.stats
+    }
+}
+
+/// Counters from the previous sample, used to turn totals into rates.
+struct Previous {
+    read_bytes: u64,
+    written_bytes: u64,
+    context_switches: Option<u64>,
+    time: Instant,
+}
+
+/// Samples extended per-process metrics for the zap.
+pub struct ExtendedMonitor {
+    previous: Option<Previous>,
+}
+
+impl ExtendedMonitor {
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.no_metrics || args.metrics_level != MetricsLevel::Extended {
+            return None;
+        }
+
+        Some(ExtendedMonitor { previous: None })
+    }
+
+    pub fn sample(&mut self, process: &Process) -> ExtendedMetrics {
+        let disk_usage = process.disk_usage();
+        let pid = process.pid().as_u32();
+        let status = read_proc_status(pid);
+        let context_switches = status.voluntary_context_switches
+            .zip(status.involuntary_context_switches)
+            .map(|(voluntary, involuntary)| voluntary + involuntary);
+
+        let now = Instant::now();
+        let mut read_rate = 0.0;
+        let mut write_rate = 0.0;
+        let mut context_switch_rate = None;
+
+        if let Some(previous) = &self.previous {
+            let elapsed = now.duration_since(previous.time).as_secs_f64().max(f64::EPSILON);
+            read_rate = disk_usage.total_read_bytes.saturating_sub(previous.read_bytes) as f64 / elapsed;
+            write_rate = disk_usage.total_written_bytes.saturating_sub(previous.written_bytes) as f64 / elapsed;
+            context_switch_rate = context_switches
+                .zip(previous.context_switches)
+                .map(|(current, previous)| current.saturating_sub(previous) as f64 / elapsed);
+        }
+
+        self.previous = Some(Previous {
+            read_bytes: disk_usage.total_read_bytes,
+            written_bytes: disk_usage.total_written_bytes,
+            context_switches,
+            time: now,
+        });
+
+        ExtendedMetrics {
+            virtual_memory: process.virtual_memory(),
+            read_bytes: disk_usage.total_read_bytes,
+            written_bytes: disk_usage.total_written_bytes,
+            read_rate,
+            write_rate,
+            status: process.status().to_string(),
+            start_time: process.start_time(),
+            threads: status.threads,
+            open_fds: count_open_fds(pid),
+            voluntary_context_switches: status.voluntary_context_switches,
+            involuntary_context_switches: status.involuntary_context_switches,
+            context_switch_rate,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ProcStatus {
+    threads: Option<u64>,
+    voluntary_context_switches: Option<u64>,
+    involuntary_context_switches: Option<u64>,
+}
+
+#[cfg(target_os = "linux")]
+fn read_proc_status(pid: u32) -> ProcStatus {
+    let mut status = ProcStatus::default();
+    let Ok(content) = std::fs::read_to_string(format!("/proc/{}/status", pid)) else {
+        return status;
+    };
+
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().parse().ok();
+
+        match key {
+            "Threads" => status.threads = value,
+            "voluntary_ctxt_switches" => status.voluntary_context_switches = value,
+            "nonvoluntary_ctxt_switches" => status.involuntary_context_switches = value,
+            _ => {}
+        }
+    }
+
+    status
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_proc_status(_pid: u32) -> ProcStatus {
+    ProcStatus::default()
+}
+
+#[cfg(target_os = "linux")]
+fn count_open_fds(pid: u32) -> Option<u64> {
+    std::fs::read_dir(format!("/proc/{}/fd", pid))
+        .map(|entries| entries.count() as u64)
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn count_open_fds(_pid: u32) -> Option<u64> {
+    None
+}
@@ -0,0 +1,342 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use ulid::Ulid;
+use bb_eye::types::{BrainCommand, CommandKind, Encoding, Exit, Introduction, Zap, ENCODINGS_HEADER, PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER};
+
+/// Longest a command poll is held open when no command is scripted
+const MAX_POLL_WAIT: Duration = Duration::from_secs(5);
+
+/// A scripted answer to requests of one endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    /// `introduction`, `zap`, `exit` or `commands`
+    pub endpoint: String,
+    /// Requests of the endpoint answered normally before the rule applies
+    #[serde(default)]
+    pub skip: usize,
+    /// Requests the rule answers, every following one if not given
+    #[serde(default)]
+    pub times: Option<usize>,
+    #[serde(flatten)]
+    pub response: MockResponse,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MockResponse {
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// Seconds to wait before answering
+    #[serde(default)]
+    pub delay: Option<f64>,
+    /// Seconds sent in a `Retry-After` header
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+    /// Uuid answered to an introduction
+    #[serde(default)]
+    pub uuid: Option<String>,
+    /// Command answered as msgpack
+    #[serde(default)]
+    pub command: Option<CommandKind>,
+    #[serde(default)]
+    pub arguments: HashMap<String, String>,
+    /// Plain text body, as older brains send `restart` and `exit`
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+/// Every payload the mock accepted, in order
+#[derive(Debug, Default, Serialize)]
+pub struct Received {
+    pub introductions: Vec<Introduction>,
+    pub zaps: Vec<Zap>,
+    pub exits: Vec<Exit>,
+    /// Uuids of the command polls
+    pub polls: Vec<String>,
+    /// Encoding of every accepted payload, in order
+    pub encodings: Vec<Encoding>,
+    /// Idempotency keys of zaps and exits dropped because they were already received
+    pub duplicates: Vec<String>,
+}
+
+#[derive(Default)]
+struct MockState {
+    received: Received,
+    /// Encodings announced to the eye, payloads in others are refused
+    accepted: Vec<Encoding>,
+    rules: Vec<Rule>,
+    /// Requests seen per endpoint, including rejected ones
+    requests: HashMap<String, usize>,
+    /// Uuid assigned per run id, a retried introduction gets the same one like from the real brain
+    uuids: HashMap<String, String>,
+    /// Idempotency keys of the zaps and exits kept
+    keys: HashSet<String>,
+}
+
+impl MockState {
+    /// Count the request and take the first rule that applies to it
+    fn next_response(&mut self, endpoint: &str) -> Option<MockResponse> {
+        let index = self.requests.entry(endpoint.to_string()).or_default();
+        let request = *index;
+        *index += 1;
+
+        let rule = self.rules.iter_mut()
+            .find(|rule| rule.endpoint == endpoint && request >= rule.skip && rule.times != Some(0))?;
+        if let Some(times) = rule.times.as_mut() {
+            *times -= 1;
+        }
+        Some(rule.response.clone())
+    }
+
+    /// False if a payload with the key was kept before, payloads without a key are always new
+    fn is_new(&mut self, key: &str) -> bool {
+        if key.is_empty() || self.keys.insert(key.to_string()) {
+            return true;
+        }
+        self.received.duplicates.push(key.to_string());
+        false
+    }
+}
+
+type MockBody = Full<Bytes>;
+
+/// Serve a stand-in brain on localhost, returns the process exit code on failure.
+///
+/// Payloads are decoded into the eye's types and kept in memory. `GET /mock/received`
+/// returns them as JSON, `POST /mock/script` adds a JSON list of rules and
+/// `POST /mock/reset` forgets payloads and rules. Every response announces the
+/// protocol version and the `accepted` encodings, all of them if empty.
+pub async fn serve(port: u16, script: Option<&str>, accepted: &[Encoding]) -> i32 {
+    let accepted = if accepted.is_empty() { Encoding::ALL.to_vec() } else { accepted.to_vec() };
+    let mut state = MockState { accepted: accepted.clone(), ..Default::default() };
+    if let Some(path) = script {
+        match std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|script| serde_json::from_str::<Vec<Rule>>(&script).map_err(|e| e.to_string())) {
+            Ok(rules) => state.rules = rules,
+            Err(e) => {
+                error!("Failed to read mock script {}: {}", path, e);
+                return 2;
+            }
+        }
+    }
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on port {}: {}", port, e);
+            return 2;
+        }
+    };
+
+    // printed on stdout so a test can start the mock on port 0 and read the endpoint
+    match listener.local_addr() {
+        Ok(addr) => println!("Listening on http://{}/telemetry", addr),
+        Err(e) => error!("Failed to read the mock address: {}", e),
+    }
+
+    let state = Arc::new(Mutex::new(state));
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        let accepted = accepted.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let accepted = accepted.clone();
+                let response = handle(request, state.clone());
+                async move { response.await.map(|response| announce(response, &accepted)) }
+            });
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                debug!("Mock connection closed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(request: Request<Incoming>, state: Arc<Mutex<MockState>>) -> Result<Response<MockBody>, Infallible> {
+    let method = request.method().clone();
+    // payloads without a content type are msgpack, as older eyes send them
+    let encoding = match request.headers().get("Content-Type").map(|value| value.to_str().unwrap_or("")) {
+        Some(content_type) => Encoding::from_content_type(content_type),
+        None => Some(Encoding::Msgpack),
+    };
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().unwrap_or("").to_string();
+    let body = match request.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return Ok(respond(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+    debug!("Mock received {} {} with {} bytes", method, path, body.len());
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match (&method, segments.as_slice()) {
+        (&Method::GET, ["mock", "received"]) => {
+            let received = serde_json::to_string(&state.lock().unwrap().received).unwrap_or_default();
+            respond(StatusCode::OK, received)
+        },
+        (&Method::POST, ["mock", "script"]) => match serde_json::from_slice::<Vec<Rule>>(&body) {
+            Ok(rules) => {
+                state.lock().unwrap().rules.extend(rules);
+                respond(StatusCode::OK, "")
+            },
+            Err(e) => respond(StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
+        },
+        (&Method::POST, ["mock", "reset"]) => {
+            let mut state = state.lock().unwrap();
+            *state = MockState { accepted: std::mem::take(&mut state.accepted), ..Default::default() };
+            respond(StatusCode::OK, "")
+        },
+        (&Method::POST, [.., endpoint @ ("introduction" | "zap" | "exit")]) => telemetry(endpoint, encoding, &body, &state).await,
+        (&Method::GET, [.., "commands", uuid]) => poll(uuid, &query, &state).await,
+        _ => respond(StatusCode::NOT_FOUND, ""),
+    };
+
+    Ok(response)
+}
+
+async fn telemetry(endpoint: &str, encoding: Option<Encoding>, body: &[u8], state: &Mutex<MockState>) -> Response<MockBody> {
+    let Some(encoding) = encoding.filter(|encoding| state.lock().unwrap().accepted.contains(encoding)) else {
+        return respond(StatusCode::UNSUPPORTED_MEDIA_TYPE, "");
+    };
+
+    let scripted = state.lock().unwrap().next_response(endpoint).unwrap_or_default();
+    let delay = scripted.delay.map(Duration::from_secs_f64).unwrap_or_default();
+
+    let status = scripted.status.and_then(|status| StatusCode::from_u16(status).ok());
+    if let Some(status) = status.filter(|status| !status.is_success()) {
+        tokio::time::sleep(delay).await;
+        let mut response = respond(status, scripted.body.unwrap_or_default());
+        if let Some(seconds) = scripted.retry_after {
+            response.headers_mut().insert("Retry-After", seconds.into());
+        }
+        return response;
+    }
+
+    let response = accept(endpoint, encoding, body, scripted, status, &mut state.lock().unwrap());
+    // the payload is kept before the delay, like a slow brain that answers after storing it
+    tokio::time::sleep(delay).await;
+    response
+}
+
+fn accept(endpoint: &str, encoding: Encoding, body: &[u8], scripted: MockResponse, status: Option<StatusCode>, state: &mut MockState) -> Response<MockBody> {
+    let decoded = match endpoint {
+        // a scripted command is answered instead of the uuid, the eye introduces the run again later
+        "introduction" if scripted.command.is_some() => encoding.decode::<Introduction>(body).map(|introduction| {
+            state.received.introductions.push(introduction);
+            None
+        }),
+        "introduction" => encoding.decode::<Introduction>(body).map(|introduction| {
+            let uuid = scripted.uuid.clone()
+                .or_else(|| state.uuids.get(&introduction.run.run_id).cloned())
+                .unwrap_or_else(|| Ulid::new().to_string());
+            state.uuids.insert(introduction.run.run_id.clone(), uuid.clone());
+            state.received.introductions.push(introduction);
+            Some(uuid)
+        }),
+        "zap" => encoding.decode::<Zap>(body).map(|zap| {
+            if state.is_new(&zap.idempotency_key) {
+                state.received.zaps.push(zap);
+            }
+            None
+        }),
+        _ => encoding.decode::<Exit>(body).map(|exit| {
+            if state.is_new(&exit.idempotency_key) {
+                state.received.exits.push(exit);
+            }
+            None
+        }),
+    };
+    if decoded.is_ok() {
+        state.received.encodings.push(encoding);
+    }
+
+    match decoded {
+        Ok(Some(uuid)) => respond(status.unwrap_or(StatusCode::CREATED), uuid),
+        Ok(None) => command_response(scripted, status.unwrap_or(StatusCode::OK)),
+        Err(e) => {
+            error!("Failed to decode {}: {}", endpoint, e);
+            respond(StatusCode::UNPROCESSABLE_ENTITY, e.to_string())
+        },
+    }
+}
+
+/// Hold the poll until the wait the eye asked for is over, unless a command is scripted
+async fn poll(uuid: &str, query: &str, state: &Mutex<MockState>) -> Response<MockBody> {
+    let scripted = {
+        let mut state = state.lock().unwrap();
+        state.received.polls.push(uuid.to_string());
+        state.next_response("commands")
+    };
+
+    let Some(scripted) = scripted else {
+        let wait = query.split('&')
+            .find_map(|pair| pair.strip_prefix("wait="))
+            .and_then(|wait| wait.parse::<f64>().ok())
+            .map(Duration::from_secs_f64)
+            .unwrap_or(MAX_POLL_WAIT);
+        tokio::time::sleep(wait.min(MAX_POLL_WAIT)).await;
+        return respond(StatusCode::NO_CONTENT, "");
+    };
+
+    if let Some(delay) = scripted.delay {
+        tokio::time::sleep(Duration::from_secs_f64(delay)).await;
+    }
+    let status = scripted.status.and_then(|status| StatusCode::from_u16(status).ok()).unwrap_or(StatusCode::OK);
+    command_response(scripted, status)
+}
+
+fn command_response(scripted: MockResponse, status: StatusCode) -> Response<MockBody> {
+    let Some(kind) = scripted.command else {
+        return respond(status, scripted.body.unwrap_or_default());
+    };
+
+    let command = BrainCommand {
+        id: Ulid::new().to_string(),
+        kind,
+        arguments: scripted.arguments,
+        issued_at: Utc::now().timestamp_millis() as u64,
+    };
+    match rmp_serde::to_vec_named(&command) {
+        Ok(body) => {
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+            *response.status_mut() = status;
+            response.headers_mut().insert("Content-Type", "application/msgpack".parse().unwrap());
+            response
+        },
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// Add the handshake headers the real brain sends with every response
+fn announce(mut response: Response<MockBody>, accepted: &[Encoding]) -> Response<MockBody> {
+    let encodings = accepted.iter().map(Encoding::name).collect::<Vec<_>>().join(",");
+    let headers = response.headers_mut();
+    headers.insert(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION.into());
+    if let Ok(encodings) = encodings.parse() {
+        headers.insert(ENCODINGS_HEADER, encodings);
+    }
+    response
+}
+
+fn respond(status: StatusCode, body: impl Into<String>) -> Response<MockBody> {
+    let mut response = Response::new(Full::new(Bytes::from(body.into())));
+    *response.status_mut() = status;
+    response
+}
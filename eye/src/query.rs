@@ -0,0 +1,158 @@
+use std::io::{self, Write};
+use std::path::Path;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::error;
+use rusqlite::{params, Connection, OpenFlags};
+use crate::history;
+use crate::types::{Args, MetricsFormat, Query};
+
+/// Run a history query subcommand, returns the process exit code
+pub fn run(query: &Query, args: &Args) -> i32 {
+    let Some(path) = &args.history else {
+        error!("No history database given, pass --history or set BB_EYE_HISTORY");
+        return 2;
+    };
+    if !Path::new(path).exists() {
+        error!("History database {} does not exist", path);
+        return 2;
+    }
+
+    // read only, a query never changes the database or its journal mode under a running eye
+    let result = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|e| e.into()).and_then(|connection| match query {
+        Query::History { limit, run } => list_runs(&connection, *limit, run.as_deref()),
+        Query::Logs { since, grep, run } => print_logs(&connection, *since, grep.as_ref(), run.as_deref()),
+        Query::Metrics { since, run, format } => print_metrics(&connection, *since, run.as_deref(), *format),
+        Query::Replay { .. } | Query::BrainMock { .. } | Query::Schema { .. } | Query::Run { .. } | Query::Attach { .. } | Query::List => unreachable!("subcommands without history are run from main"),
+    });
+
+    match result {
+        Ok(()) => 0,
+        // the reader went away, e.g. piped into head
+        Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) => 0,
+        Err(e) => {
+            error!("Failed to query history: {}", e);
+            1
+        }
+    }
+}
+
+/// Parse a relative age such as `30s`, `15m`, `2h` or `7d`, or an RFC 3339 time, into epoch milliseconds
+pub fn parse_since(since: &str) -> Result<i64, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(since) {
+        return Ok(time.timestamp_millis());
+    }
+
+    let split = since.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(since.len());
+    let (amount, unit) = since.split_at(split);
+    let amount: f64 = amount.parse().map_err(|_| format!("expected an age like 15m or an RFC 3339 time, got `{}`", since))?;
+    let seconds = match unit {
+        "s" | "" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        _ => return Err(format!("unknown unit `{}`, use s, m, h or d", unit)),
+    };
+
+    Ok(Utc::now().timestamp_millis() - (amount * seconds * 1000.0) as i64)
+}
+
+/// A LIKE pattern matching ids that start with the prefix, taking `%` and `_` in it literally
+fn prefix_pattern(prefix: Option<&str>) -> String {
+    let mut pattern = String::new();
+    for c in prefix.unwrap_or("").chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+fn format_time(millis: i64) -> String {
+    DateTime::from_timestamp_millis(millis).map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true)).unwrap_or_default()
+}
+
+type QueryResult = Result<(), Box<dyn std::error::Error>>;
+
+fn list_runs(connection: &Connection, limit: usize, run: Option<&str>) -> QueryResult {
+    let mut out = io::stdout().lock();
+    let mut statement = connection.prepare(
+        "SELECT run_id, session_id, restart_index, name, args, started, ended, exit_code FROM runs
+         WHERE run_id LIKE ?1 ESCAPE '\\' ORDER BY started DESC LIMIT ?2",
+    )?;
+    let rows = statement.query_map(params![prefix_pattern(run), limit as i64], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, u32>(2)?,
+            format!("{} {}", row.get::<_, String>(3)?, row.get::<_, String>(4)?),
+            row.get::<_, i64>(5)?,
+            row.get::<_, Option<i64>>(6)?,
+            row.get::<_, Option<i32>>(7)?,
+        ))
+    })?;
+
+    writeln!(out, "{:<26}  {:<26}  {:>7}  {:<24}  {:>9}  {:>4}  COMMAND", "RUN", "SESSION", "RESTART", "STARTED", "RUNTIME", "EXIT")?;
+    for row in rows {
+        let (run_id, session_id, restart_index, command, started, ended, exit_code) = row?;
+        let runtime = ended.map(|ended| format!("{:.1}s", (ended - started) as f64 / 1000.0)).unwrap_or_else(|| "-".to_string());
+        let exit_code = exit_code.map(|code| code.to_string()).unwrap_or_else(|| "-".to_string());
+        writeln!(out, "{:<26}  {:<26}  {:>7}  {:<24}  {:>9}  {:>4}  {}", run_id, session_id, restart_index, format_time(started), runtime, exit_code, command.trim())?;
+    }
+
+    Ok(())
+}
+
+fn print_logs(connection: &Connection, since: Option<i64>, grep: Option<&regex::Regex>, run: Option<&str>) -> QueryResult {
+    let mut out = io::stdout().lock();
+    // databases of older eyes have no source, their lines all came from the command
+    let source = if history::has_column(connection, "messages", "source")? { "source" } else { "NULL" };
+    let mut statement = connection.prepare(&format!(
+        "SELECT run_id, time, error, message, {} FROM messages
+         WHERE time >= ?1 AND run_id LIKE ?2 ESCAPE '\\' ORDER BY time, rowid",
+        source,
+    ))?;
+    let rows = statement.query_map(params![since.unwrap_or(0), prefix_pattern(run)], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, bool>(2)?, row.get::<_, String>(3)?, row.get::<_, Option<String>>(4)?))
+    })?;
+
+    for row in rows {
+        let (run_id, time, error, message, source) = row?;
+        if grep.is_some_and(|grep| !grep.is_match(&message)) {
+            continue;
+        }
+        // lines of tailed files are labelled with their file instead of a stream
+        let stream = source.unwrap_or_else(|| if error { "stderr" } else { "stdout" }.to_string());
+        writeln!(out, "{} {} {} {}", format_time(time), run_id, stream, message)?;
+    }
+
+    Ok(())
+}
+
+fn print_metrics(connection: &Connection, since: Option<i64>, run: Option<&str>, format: MetricsFormat) -> QueryResult {
+    let mut out = io::stdout().lock();
+    let mut statement = connection.prepare(
+        "SELECT run_id, time, memory, cpu, disk, zap FROM zaps
+         WHERE time >= ?1 AND run_id LIKE ?2 ESCAPE '\\' ORDER BY time, rowid",
+    )?;
+    let mut rows = statement.query(params![since.unwrap_or(0), prefix_pattern(run)])?;
+
+    match format {
+        MetricsFormat::Csv => {
+            writeln!(out, "run_id,time,memory,cpu,disk")?;
+            while let Some(row) = rows.next()? {
+                let disk = row.get::<_, Option<i64>>(4)?.map(|disk| disk.to_string()).unwrap_or_default();
+                writeln!(out, "{},{},{},{},{}", row.get::<_, String>(0)?, format_time(row.get(1)?), row.get::<_, f64>(2)?, row.get::<_, f64>(3)?, disk)?;
+            }
+        },
+        MetricsFormat::Json => {
+            // one zap per line, with every metric that was recorded
+            while let Some(row) = rows.next()? {
+                writeln!(out, "{}", row.get::<_, String>(5)?)?;
+            }
+        },
+    }
+
+    Ok(())
+}
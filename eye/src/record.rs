@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use tokio::time::Instant;
+use crate::brains::Brains;
+use crate::telemetry::TelemetryClient;
+use crate::types::{Args, Endpoint, Exit, Introduction, Zap};
+
+/// Start of every recording, the last byte is the format version
+const MAGIC: &[u8; 6] = b"BBREC\x01";
+
+/// Payloads as they were handed to the brains, written to the file given with `--record`
+pub struct Recording {
+    recorder: Recorder,
+    handle: JoinHandle<()>,
+}
+
+/// Adds frames to a recording from where payloads are sent, does nothing once the recording is closed
+#[derive(Clone)]
+pub struct Recorder(Arc<Mutex<Option<Sender<Frame>>>>);
+
+/// A payload as it was handed to the brains
+struct Frame {
+    /// Milliseconds since the epoch when the payload was sent
+    time: u64,
+    endpoint: String,
+    payload: Vec<u8>,
+}
+
+impl Recording {
+    /// Create the file and write every payload into it, `None` without `--record` or if it cannot be created.
+    ///
+    /// Payloads are recorded even with `--prevent-telemetry`, so a run without a brain can be replayed later.
+    /// Frames are written on a background thread so a slow disk never delays sampling.
+    pub fn open(args: &Args) -> Option<Self> {
+        let path = args.record.as_ref()?;
+        let file = File::create(path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            writer.write_all(MAGIC)?;
+            writer.flush()?;
+            Ok(writer)
+        });
+
+        match file {
+            Ok(writer) => {
+                let (sender, receiver) = channel();
+                let handle = thread::spawn(move || write(writer, receiver));
+                Some(Recording { recorder: Recorder(Arc::new(Mutex::new(Some(sender)))), handle })
+            },
+            Err(e) => {
+                error!("Failed to create recording {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Wait for every frame to be written
+    pub fn close(self) {
+        // taken from every recorder, a brain still held by a stopping task cannot keep the writer open
+        self.recorder.0.lock().unwrap().take();
+        let _ = self.handle.join();
+    }
+
+    /// Record a payload now, for payloads that are never sent
+    pub fn record<T: Endpoint>(&self, payload: &T) {
+        self.recorder.record(payload);
+    }
+
+    pub fn recorder(&self) -> Recorder {
+        self.recorder.clone()
+    }
+}
+
+impl Recorder {
+    /// Record a payload as it is sent, before the brains stamp it with their uuid
+    pub fn record<T: Endpoint>(&self, payload: &T) {
+        let Some(sender) = self.0.lock().unwrap().clone() else {
+            return;
+        };
+
+        let frame = match payload.to_vec() {
+            Ok(bytes) => Frame { time: Utc::now().timestamp_millis() as u64, endpoint: payload.endpoint().to_string(), payload: bytes },
+            Err(e) => {
+                error!("Failed to encode {} for the recording: {}", payload.endpoint(), e);
+                return;
+            }
+        };
+
+        // the writer is gone once writing failed, that was logged there
+        let _ = sender.send(frame);
+    }
+}
+
+/// Write frames as they come, flushed whenever the queue is empty so a killed eye still leaves a usable recording
+fn write(mut writer: BufWriter<File>, receiver: Receiver<Frame>) {
+    while let Ok(frame) = receiver.recv() {
+        let result = std::iter::once(frame).chain(receiver.try_iter())
+            .try_for_each(|frame| write_frame(&mut writer, &frame))
+            .and_then(|_| writer.flush());
+
+        if let Err(e) = result {
+            error!("Failed to write recording, stopping it: {}", e);
+            return;
+        }
+    }
+}
+
+fn write_frame(writer: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    writer.write_all(&frame.time.to_be_bytes())?;
+    writer.write_all(&[frame.endpoint.len() as u8])?;
+    writer.write_all(frame.endpoint.as_bytes())?;
+    writer.write_all(&(frame.payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&frame.payload)
+}
+
+/// Returns `None` at the end of the recording
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<Frame>> {
+    let mut time = [0; 8];
+    match reader.read_exact(&mut time) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut length = [0; 1];
+    reader.read_exact(&mut length)?;
+    let mut endpoint = vec![0; length[0] as usize];
+    reader.read_exact(&mut endpoint)?;
+
+    let mut length = [0; 4];
+    reader.read_exact(&mut length)?;
+    let mut payload = vec![0; u32::from_be_bytes(length) as usize];
+    reader.read_exact(&mut payload)?;
+
+    Ok(Some(Frame {
+        time: u64::from_be_bytes(time),
+        endpoint: String::from_utf8_lossy(&endpoint).to_string(),
+        payload,
+    }))
+}
+
+/// Send a recording to the brains given with `-e`, returns the process exit code.
+///
+/// Frames are sent with their original spacing, or back to back with `fast`. Every
+/// recorded run is introduced again and its payloads carry the uuid the brain assigns.
+pub async fn replay(path: &str, fast: bool, args: &Args) -> i32 {
+    let client = TelemetryClient::from_args(args);
+
+    let mut reader = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            error!("Failed to open recording {}: {}", path, e);
+            return 2;
+        }
+    };
+
+    let mut magic = [0; 6];
+    if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+        error!("{} is not a bb_eye recording", path);
+        return 2;
+    }
+
+    let started = Instant::now();
+    let mut first_time = None;
+    let mut runs: HashMap<String, Brains> = HashMap::new();
+    let mut sent = 0;
+
+    loop {
+        let frame = match read_frame(&mut reader) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                // a recording cut off mid-frame still replays up to the cut
+                warn!("Recording {} ends in a partial frame: {}", path, e);
+                break;
+            }
+        };
+
+        if !fast {
+            let offset = frame.time.saturating_sub(*first_time.get_or_insert(frame.time));
+            tokio::time::sleep_until(started + Duration::from_millis(offset)).await;
+        }
+
+        match replay_frame(&frame, args, &client, &mut runs).await {
+            Ok(true) => sent += 1,
+            Ok(false) => {},
+            Err(e) => error!("Failed to replay {}: {}", frame.endpoint, e),
+        }
+    }
+
+    info!("Replayed {} payloads from {}", sent, path);
+    0
+}
+
+/// Returns true if the frame was sent
+async fn replay_frame(frame: &Frame, args: &Args, client: &TelemetryClient, runs: &mut HashMap<String, Brains>) -> Result<bool, Box<dyn std::error::Error>> {
+    match frame.endpoint.as_str() {
+        "introduction" => {
+            let introduction: Introduction = rmp_serde::from_slice(&frame.payload)?;
+            let brains = Brains::from_args(args, client.clone(), introduction.run.clone());
+            let commands = brains.introduce(introduction).await?;
+            debug!("Ignoring {} commands sent to a replay", commands.len());
+            runs.insert(brains.run().run_id.clone(), brains);
+        },
+        "zap" => {
+            let zap: Zap = rmp_serde::from_slice(&frame.payload)?;
+            let Some(brains) = runs.get(&zap.run.run_id) else {
+                warn!("Skipping zap of run {} that was not introduced in the recording", zap.run.run_id);
+                return Ok(false);
+            };
+            let commands = brains.send(&zap).await?;
+            debug!("Ignoring {} commands sent to a replay", commands.len());
+        },
+        "exit" => {
+            let exit: Exit = rmp_serde::from_slice(&frame.payload)?;
+            let Some(brains) = runs.remove(&exit.run.run_id) else {
+                warn!("Skipping exit of run {} that was not introduced in the recording", exit.run.run_id);
+                return Ok(false);
+            };
+            brains.send(&exit).await?;
+        },
+        endpoint => {
+            warn!("Skipping unknown payload {} in the recording", endpoint);
+            return Ok(false);
+        },
+    }
+
+    Ok(true)
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::{brains, daemon};
+use crate::telemetry::TelemetryClient;
+use crate::types::{Args, BrainCommand, CircuitState, CommandKind, RunIdentity};
+
+/// A running eye, kept in `{runtime dir}/{pid}-{session id}.json` while it runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub pid: u32,
+    /// Uuid the brain assigned to the current run, the run id until one has
+    pub uuid: String,
+    pub run_id: String,
+    pub session_id: String,
+    pub display_name: Option<String>,
+    pub command: String,
+    /// Unix socket answering `status` and taking commands such as `restart`
+    pub control_socket: Option<String>,
+    /// Output file of a detached eye
+    pub log: Option<String>,
+    pub pidfile: Option<String>,
+    /// Milliseconds since the epoch
+    pub started: u64,
+}
+
+/// Reply to `status`, the entry with the state of the eye that changes too often to be written to it
+#[derive(Debug, Serialize)]
+struct Status<'a> {
+    #[serde(flatten)]
+    entry: &'a Entry,
+    /// Circuit state per telemetry endpoint
+    circuits: HashMap<String, CircuitState>,
+}
+
+/// What the control socket reads and changes, shared with its connections
+struct Control {
+    entry: Mutex<Entry>,
+    /// Commands of the control socket go to the run going on, if any
+    commands: Mutex<Option<mpsc::Sender<BrainCommand>>>,
+    client: TelemetryClient,
+    urls: Vec<String>,
+}
+
+/// This eye in the runtime directory, its entry and control socket are removed when it is dropped
+pub struct Registration {
+    path: PathBuf,
+    control: Arc<Control>,
+    listener: Option<JoinHandle<()>>,
+}
+
+/// `--runtime-dir`, or `bb_eye` in `XDG_RUNTIME_DIR`, or a directory per user in the temp dir
+pub fn runtime_dir(args: &Args) -> PathBuf {
+    if let Some(dir) = &args.runtime_dir {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        return PathBuf::from(dir).join("bb_eye");
+    }
+
+    #[cfg(unix)]
+    let dir = format!("bb_eye-{}", unsafe { libc::getuid() });
+    #[cfg(not(unix))]
+    let dir = "bb_eye".to_string();
+    std::env::temp_dir().join(dir)
+}
+
+impl Registration {
+    /// Register this eye and listen on its control socket, `None` if the runtime directory cannot be created.
+    ///
+    /// Files are named after the pid and the session, so every supervisor of a program has its own.
+    pub fn open(args: &Args, command: &str, session_id: &str, client: &TelemetryClient) -> Option<Self> {
+        let dir = runtime_dir(args);
+        if let Err(e) = create_private_dir(&dir) {
+            warn!("Failed to create runtime directory {}, the eye is not registered: {}", dir.display(), e);
+            return None;
+        }
+
+        let pid = std::process::id();
+        let entry = Entry {
+            pid,
+            uuid: String::new(),
+            run_id: String::new(),
+            session_id: session_id.to_string(),
+            display_name: args.display_name.clone(),
+            command: command.to_string(),
+            control_socket: None,
+            log: daemon::log_path(),
+            pidfile: args.pidfile.clone(),
+            started: Utc::now().timestamp_millis() as u64,
+        };
+        let control = Arc::new(Control {
+            entry: Mutex::new(entry),
+            commands: Mutex::new(None),
+            client: client.clone(),
+            urls: brains::endpoints(args, client),
+        });
+
+        let name = format!("{}-{}", pid, session_id);
+        let socket = dir.join(format!("{}.sock", name));
+        let listener = listen(&socket, control.clone());
+        if listener.is_some() {
+            control.entry.lock().unwrap().control_socket = Some(socket.to_string_lossy().to_string());
+        }
+
+        let registration = Registration { path: dir.join(format!("{}.json", name)), control, listener };
+        write(&registration.path, &registration.control.entry.lock().unwrap());
+        Some(registration)
+    }
+
+    /// Keep the entry up to date with the run going on
+    pub fn update_run(&self, uuid: &str, run: &RunIdentity) {
+        let mut entry = self.control.entry.lock().unwrap();
+        entry.uuid = uuid.to_string();
+        entry.run_id = run.run_id.clone();
+        entry.session_id = run.session_id.clone();
+        write(&self.path, &entry);
+    }
+
+    /// Route control socket commands to a run, `None` between runs
+    pub fn set_commands(&self, sender: Option<mpsc::Sender<BrainCommand>>) {
+        *self.control.commands.lock().unwrap() = sender;
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(listener) = &self.listener {
+            listener.abort();
+        }
+        remove(&self.control.entry.lock().unwrap(), &self.path);
+    }
+}
+
+fn write(path: &Path, entry: &Entry) {
+    let result = serde_json::to_vec_pretty(entry).map_err(io::Error::from).and_then(|json| {
+        // written next to the entry and renamed, so `list` never reads half an entry
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, json)?;
+        fs::rename(&temporary, path)
+    });
+
+    if let Err(e) = result {
+        error!("Failed to write registry entry {}: {}", path.display(), e);
+    }
+}
+
+fn remove(entry: &Entry, path: &Path) {
+    let _ = fs::remove_file(path);
+    if let Some(socket) = &entry.control_socket {
+        let _ = fs::remove_file(socket);
+    }
+}
+
+pub(crate) fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Answer control connections, one line in and one line out per request
+#[cfg(unix)]
+fn listen(path: &Path, control: Arc<Control>) -> Option<JoinHandle<()>> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    // the name is unique, a file in its place belongs to someone else and is left alone
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen for control commands on {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    debug!("Listening for control commands on {}", path.display());
+    Some(tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Failed to accept control connection: {}", e);
+                    return;
+                }
+            };
+
+            let control = control.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = control.reply(&line).await;
+                    if writer.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn listen(_path: &Path, _control: Arc<Control>) -> Option<JoinHandle<()>> {
+    None
+}
+
+impl Control {
+    /// `status` returns the entry and the circuit states as JSON, anything else is a command like `signal signal=HUP`
+    async fn reply(&self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let Some(kind) = words.next() else {
+            return "error: empty request".to_string();
+        };
+
+        if kind == "status" {
+            let circuits = self.urls.iter().map(|url| (url.clone(), self.client.circuit_state(url))).collect();
+            return serde_json::to_string(&Status { entry: &self.entry.lock().unwrap(), circuits }).unwrap_or_default();
+        }
+
+        let kind = match serde_json::from_value::<CommandKind>(serde_json::Value::String(kind.to_string())) {
+            Ok(CommandKind::Unknown) | Err(_) => return format!("error: unknown command {}", kind),
+            Ok(kind) => kind,
+        };
+        let arguments: HashMap<String, String> = words
+            .filter_map(|word| word.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        // local commands carry no id, so they are not acknowledged to the brain
+        let command = BrainCommand { id: String::new(), kind, arguments, issued_at: Utc::now().timestamp_millis() as u64 };
+        let sender = self.commands.lock().unwrap().clone();
+        match sender {
+            Some(sender) if sender.send(command).await.is_ok() => "ok".to_string(),
+            _ => "error: no run going on".to_string(),
+        }
+    }
+}
+
+/// Whether the eye of an entry still runs, a reused pid is told apart by its dead control socket
+fn is_alive(entry: &Entry) -> bool {
+    if !daemon::is_running(entry.pid) {
+        return false;
+    }
+
+    #[cfg(unix)]
+    if let Some(socket) = &entry.control_socket {
+        return std::os::unix::net::UnixStream::connect(socket).is_ok();
+    }
+    true
+}
+
+/// Entries are named `{pid}-{session id}.json`, other files of the runtime directory such as tail offsets are not
+fn is_entry(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "json")
+        && path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.split_once('-')).is_some_and(|(pid, _)| pid.parse::<u32>().is_ok())
+}
+
+/// Print the eyes running on this host and remove the entries of eyes that are gone, returns the exit code
+pub fn list(args: &Args) -> i32 {
+    let dir = runtime_dir(args);
+    let files = match fs::read_dir(&dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return print(&[]).map_or(1, |_| 0),
+        Err(e) => {
+            error!("Failed to read runtime directory {}: {}", dir.display(), e);
+            return 1;
+        }
+    };
+
+    let mut entries = Vec::new();
+    for path in files.filter_map(Result::ok).map(|file| file.path()).filter(|path| is_entry(path)) {
+        let entry = match fs::read(&path).map_err(|e| e.to_string()).and_then(|json| serde_json::from_slice::<Entry>(&json).map_err(|e| e.to_string())) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Removing unreadable registry entry {}: {}", path.display(), e);
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+        };
+
+        if is_alive(&entry) {
+            entries.push(entry);
+        } else {
+            debug!("Removing registry entry of eye {}, it is no longer running", entry.pid);
+            remove(&entry, &path);
+        }
+    }
+
+    entries.sort_by_key(|entry| entry.started);
+    match print(&entries) {
+        Ok(()) => 0,
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            error!("Failed to list eyes: {}", e);
+            1
+        }
+    }
+}
+
+fn print(entries: &[Entry]) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    writeln!(out, "{:>8}  {:<26}  {:<24}  {:<40}  COMMAND", "PID", "UUID", "STARTED", "CONTROL")?;
+    for entry in entries {
+        let started = DateTime::from_timestamp_millis(entry.started as i64).map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true)).unwrap_or_default();
+        let name = entry.display_name.clone().unwrap_or_else(|| entry.command.clone());
+        writeln!(out, "{:>8}  {:<26}  {:<24}  {:<40}  {}", entry.pid, entry.uuid, started, entry.control_socket.as_deref().unwrap_or("-"), name)?;
+    }
+    Ok(())
+}
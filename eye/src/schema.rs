@@ -0,0 +1,63 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use log::{error, info};
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use serde_json::{json, Map, Value};
+use crate::types::{BrainCommand, Exit, Introduction, Zap, PROTOCOL_VERSION};
+
+/// JSON Schema of every payload by endpoint, `command` is what the brain sends back
+pub fn schemas() -> Vec<(&'static str, RootSchema)> {
+    let mut schemas = vec![
+        ("introduction", schema_for!(Introduction)),
+        ("zap", schema_for!(Zap)),
+        ("exit", schema_for!(Exit)),
+        ("command", schema_for!(BrainCommand)),
+    ];
+
+    for (_, schema) in schemas.iter_mut() {
+        schema.schema.extensions.insert("x-protocol-version".to_string(), json!(PROTOCOL_VERSION));
+    }
+    schemas
+}
+
+/// Print the schemas as one JSON object, or write `{endpoint}.schema.json` files into `out_dir`.
+/// Returns the process exit code.
+pub fn run(out_dir: Option<&str>) -> i32 {
+    let result = match out_dir {
+        Some(out_dir) => write_files(Path::new(out_dir)),
+        None => print_all(),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            error!("Failed to write schema: {}", e);
+            1
+        }
+    }
+}
+
+fn print_all() -> io::Result<()> {
+    let mut all = Map::new();
+    all.insert("protocol_version".to_string(), json!(PROTOCOL_VERSION));
+    for (endpoint, schema) in schemas() {
+        all.insert(endpoint.to_string(), serde_json::to_value(schema)?);
+    }
+
+    let mut out = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut out, &Value::Object(all))?;
+    writeln!(out)
+}
+
+fn write_files(out_dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(out_dir)?;
+    for (endpoint, schema) in schemas() {
+        let path = out_dir.join(format!("{}.schema.json", endpoint));
+        fs::write(&path, serde_json::to_vec_pretty(&schema)?)?;
+        info!("Wrote {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use log::{debug, error};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::brains::Brains;
+use crate::telemetry::TelemetryClient;
+use crate::types::{Args, BrainCommand, EyeMetrics, Zap};
+
+#[derive(Default)]
+struct SendStats {
+    latency_total: f64,
+    sends: u64,
+    dropped: u64,
+}
+
+/// Sends zaps to the brain from its own task, so a slow brain never delays sampling.
+///
+/// Zaps wait in a bounded queue; when it is full new zaps are dropped and counted.
+/// Commands found in telemetry responses are forwarded to the monitoring loop.
+pub struct ZapSender {
+    queue: mpsc::Sender<Zap>,
+    stats: Arc<Mutex<SendStats>>,
+    client: TelemetryClient,
+    urls: Vec<String>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ZapSender {
+    pub fn start(args: &Args, brains: Arc<Brains>, client: TelemetryClient, urls: Vec<String>, commands: mpsc::Sender<BrainCommand>) -> Self {
+        let (queue, mut receiver) = mpsc::channel::<Zap>(args.send_queue_size.max(1));
+        let stats = Arc::new(Mutex::new(SendStats::default()));
+
+        let task_stats = stats.clone();
+        let handle = tokio::spawn(async move {
+            while let Some(zap) = receiver.recv().await {
+                let started = Instant::now();
+                let result = brains.send(&zap).await;
+
+                if let Ok(mut stats) = task_stats.lock() {
+                    stats.latency_total += started.elapsed().as_secs_f64() * 1000.0;
+                    stats.sends += 1;
+                }
+
+                match result {
+                    Ok(received) => {
+                        // never wait on the monitoring loop, it may be waiting for this task to drain
+                        for command in received {
+                            if let Err(e) = commands.try_send(command) {
+                                debug!("Dropping command, the brain sends it again until acknowledged: {}", e);
+                            }
+                        }
+                    },
+                    Err(e) => error!("Failed to send zap: {}", e),
+                }
+            }
+        });
+
+        ZapSender { queue, stats, client, urls, handle: Some(handle) }
+    }
+
+    /// Queue a zap for sending without waiting for the brain
+    pub fn send(&self, zap: Zap) {
+        if self.queue.try_send(zap).is_err() {
+            debug!("Telemetry queue full, dropping zap");
+            if let Ok(mut stats) = self.stats.lock() {
+                stats.dropped += 1;
+            }
+        }
+    }
+
+    /// Eye metrics since the previous call
+    pub fn metrics(&self) -> EyeMetrics {
+        let mut stats = self.stats.lock().unwrap();
+        let metrics = EyeMetrics {
+            queue_depth: (self.queue.max_capacity() - self.queue.capacity()) as u64,
+            send_latency: if stats.sends > 0 { Some(stats.latency_total / stats.sends as f64) } else { None },
+            dropped_zaps: stats.dropped,
+            circuits: self.urls.iter().map(|url| (url.clone(), self.client.circuit_state(url))).collect(),
+        };
+        *stats = SendStats::default();
+        metrics
+    }
+
+    /// Wait for every queued zap to be sent
+    pub async fn drain(mut self) {
+        let handle = self.handle.take();
+        // closing the queue ends the task once it is empty
+        drop(self);
+        if let Some(handle) = handle {
+            let _ = handle.await;
+        }
+    }
+}
+
+impl Drop for ZapSender {
+    fn drop(&mut self) {
+        if let Some(handle) = &self.handle {
+            handle.abort();
+        }
+    }
+}
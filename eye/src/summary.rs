@@ -0,0 +1,110 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use std::io;
+use std::process::{Child, ExitStatus};
+use crate::metrics::ProcessStats;
+use crate::telemetry::TelemetryClient;
+use crate::types::RunSummary;
+
+/// Lines read from the output streams of the command
+#[derive(Debug, Default)]
+pub struct StreamCounts {
+    pub stdout: AtomicU64,
+    pub stderr: AtomicU64,
+    /// Lines pushed out of the message buffer before they were sent
+    pub dropped: AtomicU64,
+}
+
+/// Collects the statistics of one run for the summary in its exit.
+pub struct RunRecorder {
+    started: Instant,
+    client: TelemetryClient,
+    telemetry_failures: u64,
+    pub streams: Arc<StreamCounts>,
+}
+
+impl RunRecorder {
+    pub fn start(client: &TelemetryClient) -> Self {
+        RunRecorder {
+            started: Instant::now(),
+            client: client.clone(),
+            telemetry_failures: client.failures(),
+            streams: Arc::new(StreamCounts::default()),
+        }
+    }
+
+    /// `usage` is what `reap` read for the command, `None` for a process the eye did not spawn
+    pub fn finish(&self, stats: &ProcessStats, usage: Option<ChildUsage>) -> RunSummary {
+        let peak_memory = match (stats.peak_memory, usage.map(|usage| usage.max_rss).filter(|rss| *rss > 0.0)) {
+            (Some(sampled), Some(rss)) => Some(sampled.max(rss)),
+            (sampled, rss) => sampled.or(rss),
+        };
+
+        RunSummary {
+            runtime: self.started.elapsed().as_secs_f64(),
+            user_cpu_time: usage.map(|usage| usage.user),
+            system_cpu_time: usage.map(|usage| usage.system),
+            peak_memory,
+            average_cpu: stats.average_cpu(),
+            p95_cpu: stats.cpu_percentile(0.95),
+            peak_disk: stats.peak_disk,
+            stdout_lines: self.streams.stdout.load(Ordering::Relaxed),
+            stderr_lines: self.streams.stderr.load(Ordering::Relaxed),
+            dropped_lines: self.streams.dropped.load(Ordering::Relaxed),
+            telemetry_failures: self.client.failures() - self.telemetry_failures,
+        }
+    }
+}
+
+/// CPU seconds and peak resident bytes of a command and the children it waited for
+#[derive(Clone, Copy)]
+pub struct ChildUsage {
+    user: f64,
+    system: f64,
+    max_rss: f64,
+}
+
+/// True once the child exited, without reaping it so `reap` can still read its resource usage
+#[cfg(unix)]
+pub fn has_exited(child: &Child) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::waitid(libc::P_PID, child.id() as libc::id_t, &mut info, libc::WEXITED | libc::WNOHANG | libc::WNOWAIT) };
+    // the pid stays 0 while the child runs, an error means there is nothing left to wait for
+    result != 0 || unsafe { info.si_pid() } != 0
+}
+
+#[cfg(not(unix))]
+pub fn has_exited(child: &mut Child) -> bool {
+    !matches!(child.try_wait(), Ok(None))
+}
+
+/// Wait for the child to exit and read the resource usage of this child alone, other children of the eye do not count
+#[cfg(unix)]
+pub fn reap(child: &mut Child) -> io::Result<(ExitStatus, Option<ChildUsage>)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    while unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut usage) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    let seconds = |time: libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1_000_000.0;
+    // macOS reports the peak in bytes, everything else in kilobytes
+    let rss_unit = if cfg!(target_os = "macos") { 1.0 } else { 1024.0 };
+
+    Ok((ExitStatus::from_raw(status), Some(ChildUsage {
+        user: seconds(usage.ru_utime),
+        system: seconds(usage.ru_stime),
+        max_rss: usage.ru_maxrss as f64 * rss_unit,
+    })))
+}
+
+#[cfg(not(unix))]
+pub fn reap(child: &mut Child) -> io::Result<(ExitStatus, Option<ChildUsage>)> {
+    Ok((child.wait()?, None))
+}
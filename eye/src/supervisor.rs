@@ -0,0 +1,655 @@
+use std::process::{Command, Stdio, Child};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use sysinfo::{System, Pid, Signal};
+use std::time::Duration;
+use log::{error, debug};
+use ulid::Ulid;
+use tokio::sync::mpsc;
+use chrono::Utc;
+use std::fs::File;
+use crate::types::{Zap, Introduction, Exit, MessageBuffer, Args, BrainWaveError, BrainCommand, RunIdentity};
+use crate::utils::{read_streams, log_zap, setup_signal_handlers, process_system, OutputSinks};
+use crate::telemetry::TelemetryClient;
+use crate::metrics::Sampler;
+use crate::summary::{self, ChildUsage, RunRecorder};
+use crate::custom::CustomMetrics;
+use crate::commands::{Commands, CommandEffect, CommandChannel};
+use crate::sender::ZapSender;
+use crate::brains::Brains;
+use crate::systemd::{self, Journal, ServiceManager, StartCondition};
+use crate::attach::{Attached, StopSignal, Target};
+use crate::tail::Tailer;
+use crate::history::History;
+use crate::record::Recording;
+use crate::registry::Registration;
+use crate::{daemon, identity, labels};
+
+/// Runs a command and reports it to the brain, restarting it as the args ask.
+///
+/// This is what `bb_eye` does with its command line. Args can be built without a
+/// command line with `Args::try_parse_from(["bb_eye", "--restart", "my-service"])`.
+/// While a run is going on, SIGTERM and SIGINT to this process stop the command,
+/// unless the supervisor is built `without_signal_handlers`.
+pub struct Supervisor {
+    args: Args,
+    client: TelemetryClient,
+    manager: ServiceManager,
+    handle_signals: bool,
+}
+
+impl Supervisor {
+    pub fn from_args(args: Args) -> Self {
+        let client = TelemetryClient::from_args(&args);
+        Supervisor::with_client(args, client)
+    }
+
+    /// Supervise with a client shared with other code, so they share circuits and connections
+    pub fn with_client(args: Args, client: TelemetryClient) -> Self {
+        Supervisor { args, client, manager: ServiceManager::from_env(), handle_signals: true }
+    }
+
+    /// Leave SIGTERM and SIGINT to the program embedding the supervisor.
+    ///
+    /// Tokio keeps its handler for a signal installed once a run listened for it, so a program
+    /// that relies on the default action of these signals should build its supervisors this way.
+    pub fn without_signal_handlers(mut self) -> Self {
+        self.handle_signals = false;
+        self
+    }
+
+    pub fn client(&self) -> &TelemetryClient {
+        &self.client
+    }
+
+    /// Run the command until it is done for good, returns the exit code of the last run
+    pub async fn run(&self) -> i32 {
+        let args = &self.args;
+        let mut last_exit = -1;
+
+        // Check and fetch the command-line argument
+        let command = &args.command.join(" ");
+        let session = Session::open(self, command);
+        let name = args.display_name.as_deref().unwrap_or(command);
+
+        // One system handle is reused for every sample of every run
+        let mut sys = System::new();
+        let custom = CustomMetrics::start(args, &session.id).await;
+        let mut commands = Commands::default();
+
+        // Every run of this eye shares the session id, runs are numbered in order
+        let mut restart_index = 0;
+
+        // Run the command
+        let mut attempt_count = 0;
+        while attempt_count < args.max_restarts {
+            // restart the telemetry delay for every run
+            self.client.reset_delay();
+
+            let run = RunIdentity::new(&session.id, restart_index);
+            restart_index += 1;
+            self.manager.status(&format!("Starting {}, run {}", name, restart_index));
+
+            let result = run_command(command, &session, run, &mut sys, custom.as_ref(), &mut commands).await;
+            last_exit = *result.as_ref().unwrap_or(&-1);
+            if commands.stop_after_drain && result.is_ok() {
+                debug!("Command drained after stop request from brain - exiting");
+                break;
+            }
+
+            match result {
+                Ok(0) => {
+                    debug!("Command completed successfully - exiting");
+                    break;
+                },
+                Ok(_) => {
+                    if !(args.restart) {
+                        debug!("Command completed with non-zero exit code - exiting");
+                        break;
+                    }
+                    debug!("Command completed with non-zero exit code - restarting in {} seconds", attempt_count + 1);
+                    self.manager.status(&format!("Restarting {} in {}s after exit code {}, {} restarts", name, attempt_count + 1, last_exit, restart_index));
+                    self.manager.sleep(Duration::from_secs((attempt_count + 1).into())).await;
+                },
+                Err(BrainWaveError::RestartRequired(_)) => {
+                    debug!("Restart command received from brain");
+                    continue;
+                },
+                Err(BrainWaveError::ExitRequired(_)) => {
+                    debug!("Exit command received from brain");
+                    break;
+                },
+                Err(e) => {
+                    error!("Unknown error in top level: {} - restarting", e);
+                }
+            }
+
+            attempt_count += 1;
+        }
+
+        self.manager.stopping(&format!("Stopped {}, exit code {}", name, last_exit));
+        if let Some(custom) = custom {
+            custom.stop().await;
+        }
+        session.close();
+        last_exit
+    }
+
+    /// Watch a process the eye did not start, given with `--pid` or `--match`, returns the exit code of the last run.
+    ///
+    /// Only the parent of a process can read its exit code, so runs end with -1. A restart command
+    /// terminates the process and, with `--reattach`, waits for whatever starts it again.
+    /// SIGTERM and SIGINT to this process stop the watching and leave the process running.
+    pub async fn attach(&self) -> i32 {
+        let args = &self.args;
+        let Some(target) = Target::from_args(args) else {
+            error!("Nothing to attach to, pass --pid or --match");
+            return 2;
+        };
+        let session = Session::open(self, &format!("attach {}", target));
+        let stop = StopSignal::new(self.handle_signals);
+
+        let mut last_exit = -1;
+        let mut sys = System::new();
+        let custom = CustomMetrics::start(args, &session.id).await;
+        let mut commands = Commands::default();
+        let mut restart_index = 0;
+
+        while !stop.is_stopping() {
+            let Some(process) = Attached::find(&target) else {
+                if !args.reattach {
+                    error!("No running process for {}", target);
+                    break;
+                }
+                self.manager.status(&format!("Waiting for a process matching {}", target));
+                self.manager.sleep(Duration::from_secs_f64(args.telemetry_interval)).await;
+                continue;
+            };
+
+            self.client.reset_delay();
+            let run = RunIdentity::new(&session.id, restart_index);
+            restart_index += 1;
+            let pid = process.pid;
+            self.manager.status(&format!("Attached to {} {}, {} reattaches", pid, process.name, run.restart_index));
+
+            let result = attach_process(process, &session, run, &mut sys, custom.as_ref(), &mut commands, stop.flag()).await;
+            last_exit = *result.as_ref().unwrap_or(&-1);
+            match result {
+                Err(BrainWaveError::RestartRequired(_)) => {
+                    debug!("Restart command received from brain - terminating process {}", pid);
+                    if let Some(process) = process_system(pid).process(Pid::from_u32(pid)) {
+                        process.kill_with(Signal::Term);
+                    }
+                },
+                Err(BrainWaveError::ExitRequired(_)) => {
+                    debug!("Exit command received from brain - detaching");
+                    break;
+                },
+                Err(e) => error!("Unknown error while attached: {}", e),
+                Ok(_) => debug!("Attached process {} exited", pid),
+            }
+
+            if !args.reattach || commands.stop_after_drain {
+                break;
+            }
+        }
+
+        self.manager.stopping(&format!("Detached from {}", target));
+        if let Some(custom) = custom {
+            custom.stop().await;
+        }
+        session.close();
+        last_exit
+    }
+}
+
+/// What one `run` or `attach` of a supervisor keeps open until it returns
+struct Session<'a> {
+    /// Shared by every run, and names the files of this supervisor in the runtime directory
+    id: String,
+    args: &'a Args,
+    client: &'a TelemetryClient,
+    manager: &'a ServiceManager,
+    handle_signals: bool,
+    history: Option<History>,
+    recording: Option<Recording>,
+    registration: Option<Registration>,
+}
+
+impl<'a> Session<'a> {
+    fn open(supervisor: &'a Supervisor, command: &str) -> Self {
+        let args = &supervisor.args;
+        let id = Ulid::new().to_string();
+        Session {
+            registration: Registration::open(args, command, &id, &supervisor.client),
+            id,
+            args,
+            client: &supervisor.client,
+            manager: &supervisor.manager,
+            handle_signals: supervisor.handle_signals,
+            history: History::open(args),
+            recording: Recording::open(args),
+        }
+    }
+
+    /// Wait for the history and the recording to be written, and unregister
+    fn close(self) {
+        if let Some(history) = self.history {
+            history.close();
+        }
+        if let Some(recording) = self.recording {
+            recording.close();
+        }
+    }
+
+    /// Brains of a run, recording each payload when it is sent
+    fn brains(&self, run: &RunIdentity) -> Brains {
+        Brains::from_args(self.args, self.client.clone(), run.clone()).with_recorder(self.recording.as_ref().map(Recording::recorder))
+    }
+
+    /// Without telemetry nothing is sent, so payloads are recorded as they are built
+    fn unsent(&self) -> Option<&Recording> {
+        self.recording.as_ref().filter(|_| self.args.prevent_telemetry)
+    }
+
+    fn record_introduction(&self, introduction: &Introduction) {
+        if let Some(history) = &self.history {
+            history.record_introduction(introduction);
+        }
+        if let Some(recording) = self.unsent() {
+            recording.record(introduction);
+        }
+    }
+
+    fn record_zap(&self, zap: &Zap, messages: &[MessageBuffer]) {
+        if let Some(history) = &self.history {
+            history.record_zap(zap, messages);
+        }
+        if let Some(recording) = self.unsent() {
+            recording.record(zap);
+        }
+    }
+
+    fn record_exit(&self, exit: &Exit) {
+        if let Some(history) = &self.history {
+            history.record_exit(exit);
+        }
+        if let Some(recording) = self.unsent() {
+            recording.record(exit);
+        }
+    }
+
+    /// Route control socket commands to a run, `None` between runs
+    fn set_commands(&self, sender: Option<mpsc::Sender<BrainCommand>>) {
+        if let Some(registration) = &self.registration {
+            registration.set_commands(sender);
+        }
+    }
+}
+
+/// The process of a run, spawned by the eye or attached to
+enum Watched {
+    Child(Child),
+    /// With the flag set when the eye is asked to stop watching
+    Attached(Attached, Arc<AtomicBool>),
+}
+
+impl Watched {
+    fn id(&self) -> u32 {
+        match self {
+            Watched::Child(child) => child.id(),
+            Watched::Attached(process, _) => process.pid,
+        }
+    }
+
+    /// An attached process also counts as exited once the eye is asked to stop
+    fn has_exited(&mut self) -> bool {
+        match self {
+            Watched::Child(child) => summary::has_exited(child),
+            Watched::Attached(process, stopping) => stopping.load(Ordering::Relaxed) || process.has_exited(),
+        }
+    }
+
+    /// Stop a spawned process with SIGTERM, and SIGKILL if it is still running after a grace period
+    async fn terminate(&mut self) {
+        let Watched::Child(child) = self else {
+            return;
+        };
+
+        if let Some(process) = process_system(child.id()).process(Pid::from_u32(child.id())) {
+            process.kill_with(Signal::Term);
+        }
+        for _ in 0..50 {
+            if summary::has_exited(child) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        debug!("Child process {} still running, sending SIGKILL", child.id());
+        let _ = child.kill();
+    }
+
+    /// Reap the process, returns its exit code or -1 if it cannot be known, and the resources it used
+    fn wait(self) -> (i32, Option<ChildUsage>) {
+        let mut child = match self {
+            Watched::Child(child) => child,
+            Watched::Attached(process, _) => {
+                debug!("Process with PID {} is gone, only its parent knows the exit code", process.pid);
+                return (-1, None);
+            },
+        };
+
+        let (status, usage) = match summary::reap(&mut child) {
+            Ok(reaped) => reaped,
+            Err(e) => {
+                error!("Failed to wait on child process: {}", e);
+                return (-1, None);
+            },
+        };
+        debug!("Child process exited with status: {}", status);
+
+        if status.success() {
+            debug!("Command succeeded");
+        }
+        else {
+            error!("Command failed");
+        }
+
+        (status.code().unwrap_or(-1), usage)
+    }
+}
+
+/// Run the command once, returns its exit code
+async fn run_command(command: &str, session: &Session<'_>, run: RunIdentity, sys: &mut System, custom: Option<&CustomMetrics>, commands: &mut Commands) -> Result<i32, BrainWaveError> {
+    let (args, client) = (session.args, session.client);
+    debug!("Running command: {:?} with args: {:?}", command, args);
+
+    let parent_pid = std::process::id() as usize;
+    let root_proc = command.split(" ").next().unwrap();
+    let root_proc_args = command.split(" ").skip(1).collect::<Vec<&str>>();
+    let recorder = RunRecorder::start(client);
+
+    // Spawn the subshell process
+    let mut process = Command::new(root_proc);
+    for variable in systemd::ENVIRONMENT {
+        process.env_remove(variable);
+    }
+    process.env_remove(daemon::DETACHED_ENV);
+    let mut child = match process
+        .args(root_proc_args.clone())
+        .envs(custom.map(|custom| custom.env().to_vec()).unwrap_or_default())
+        .stderr(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to spawn process {:?} with args {:?}: {}", root_proc, root_proc_args, e);
+            return Ok(-1);
+        }
+    };
+
+    let child_pid = child.id();
+    // dropped with the run, so a later signal never reaches a pid that may be reused
+    let _signal_handlers = session.handle_signals.then(|| setup_signal_handlers(child_pid));
+
+    debug!("Monitoring process with PID: {}", child_pid);
+
+    let brains = Arc::new(session.brains(&run));
+    let introduction = Introduction::from_child(run.clone(), identity::gather(args, root_proc).await, parent_pid as i32, child_pid as i32, root_proc, &root_proc_args.join(" "), args.display_name.clone());
+    debug!("Introduction: {:?}", introduction);
+    session.record_introduction(&introduction);
+
+    if !args.prevent_telemetry {
+        introduce(session, &brains, introduction, commands).await;
+    }
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    // start a thread to read from stdout
+    let all_message_buffer = Arc::new(Mutex::new(Vec::new()));
+    let stderr_message_buffer = Arc::new(Mutex::new(Vec::new()));
+    let start = Arc::new(StartCondition::from_args(args, session.manager, args.display_name.as_deref().unwrap_or(command), run.restart_index));
+    let sinks = OutputSinks {
+        counts: recorder.streams.clone(),
+        start: start.clone(),
+        journal: Journal::from_args(args, &brains.uuid(), &run, command).map(Arc::new),
+    };
+    let tailer = Tailer::start(args, all_message_buffer.clone(), sinks.clone());
+    let readers = read_streams(stdout, stderr, all_message_buffer.clone(), stderr_message_buffer.clone(), args, custom.cloned(), sinks);
+    let ready_timer = start.watch();
+
+    let mut sampler = Sampler::new(sys, Pid::from_u32(child_pid), args);
+    let mut process = Watched::Child(child);
+
+    let result = handle_process(
+        &mut process,
+        session,
+        &mut sampler,
+        custom,
+        commands,
+        all_message_buffer.clone(),
+        brains.clone()
+    ).await;
+
+    ready_timer.abort();
+    session.set_commands(None);
+    if let Some(tailer) = tailer {
+        tailer.stop();
+    }
+
+    if result.is_err() {
+        debug!("Process with PID {} may still be alive - force quitting", child_pid);
+        session.manager.alive(process.terminate()).await;
+    }
+
+    // reaped and read to the end first, so the summary has its CPU time and every line
+    let (exit_code, usage) = process.wait();
+    readers.join();
+    let result_int = if result.is_ok() { exit_code } else { -1 };
+
+    let exit = Exit::from_status(
+        brains.uuid(),
+        run,
+        result_int,
+        if result_int == 0 { None } else { Some(stderr_message_buffer.lock().unwrap().clone()) },
+        commands.take_acks(),
+        Some(recorder.finish(sampler.stats(), usage)),
+    );
+
+    report_exit(&exit, session, &brains).await;
+
+    result.map(|_| result_int)
+}
+
+/// Watch an attached process until it is gone
+async fn attach_process(process: Attached, session: &Session<'_>, run: RunIdentity, sys: &mut System, custom: Option<&CustomMetrics>, commands: &mut Commands, stopping: Arc<AtomicBool>) -> Result<i32, BrainWaveError> {
+    let (args, client) = (session.args, session.client);
+    debug!("Attaching to process with PID {}: {} {}", process.pid, process.name, process.args);
+    let recorder = RunRecorder::start(client);
+
+    let brains = Arc::new(session.brains(&run));
+    let mut introduction = Introduction::from_child(run.clone(), identity::gather(args, &process.name).await, process.parent_pid as i32, process.pid as i32, &process.name, &process.args, args.display_name.clone());
+    if let Some(user) = &process.user {
+        introduction.user = user.clone();
+    }
+    debug!("Introduction: {:?}", introduction);
+    session.record_introduction(&introduction);
+
+    if !args.prevent_telemetry {
+        introduce(session, &brains, introduction, commands).await;
+    }
+
+    // the output of an attached process is out of reach, only tailed files fill the buffer
+    let all_message_buffer = Arc::new(Mutex::new(Vec::new()));
+    let start = Arc::new(StartCondition::from_args(args, session.manager, &process.name, run.restart_index));
+    let sinks = OutputSinks {
+        counts: recorder.streams.clone(),
+        start: start.clone(),
+        journal: Journal::from_args(args, &brains.uuid(), &run, &process.name).map(Arc::new),
+    };
+    let tailer = Tailer::start(args, all_message_buffer.clone(), sinks);
+    let ready_timer = start.watch();
+
+    let mut sampler = Sampler::new(sys, Pid::from_u32(process.pid), args);
+    let mut process = Watched::Attached(process, stopping);
+    let result = handle_process(&mut process, session, &mut sampler, custom, commands, all_message_buffer, brains.clone()).await;
+    ready_timer.abort();
+    session.set_commands(None);
+    if let Some(tailer) = tailer {
+        tailer.stop();
+    }
+
+    let result = result.map(|_| process.wait().0);
+    let exit = Exit::from_status(
+        brains.uuid(),
+        run,
+        *result.as_ref().unwrap_or(&-1),
+        None,
+        commands.take_acks(),
+        Some(recorder.finish(sampler.stats(), None)),
+    );
+    report_exit(&exit, session, &brains).await;
+
+    result
+}
+
+/// Introduce the run, commands answered to the introduction wait for the monitoring loop
+async fn introduce(session: &Session<'_>, brains: &Brains, introduction: Introduction, commands: &mut Commands) {
+    match session.manager.alive(brains.introduce(introduction)).await {
+        Ok(received) => commands.queue(received),
+        // brains without a uuid are introduced again before the next payload
+        Err(e) => error!("Failed to introduce the run: {}", e),
+    }
+}
+
+async fn report_exit(exit: &Exit, session: &Session<'_>, brains: &Brains) {
+    debug!("Exit: {:?}", exit);
+    session.record_exit(exit);
+    if !session.args.prevent_telemetry {
+        let _ = session.manager.alive(brains.send(exit)).await;
+    }
+}
+
+/// Sample and report the process until it exits or a command ends the run, the caller waits for it
+async fn handle_process(process: &mut Watched, session: &Session<'_>, sampler: &mut Sampler<'_>, custom: Option<&CustomMetrics>, commands: &mut Commands, all_message_buffer: Arc<Mutex<Vec<MessageBuffer>>>, brains: Arc<Brains>) -> Result<(), BrainWaveError> {
+    let args = session.args;
+    let pid = process.id();
+    let zap_labels = if args.zap_labels { labels::from_args(args) } else { None };
+
+    // Sampling and sending run on their own cadence, the loop wakes for whichever is due first
+    let mut send_interval = Duration::from_secs_f64(commands.interval.unwrap_or(args.telemetry_interval));
+    let mut sample_interval = args.sample_interval.map(Duration::from_secs_f64).unwrap_or(send_interval);
+    let mut next_sample = Utc::now();
+    let mut next_send = Utc::now();
+
+    // Zaps are sent from their own task, commands from responses and the push channel come back here
+    let (command_sender, mut command_receiver) = mpsc::channel(16);
+    let (client, uuid, run, introduced, urls) = (brains.client().clone(), brains.uuid(), brains.run().clone(), brains.introduced(), brains.urls());
+    for command in commands.take_queued() {
+        let _ = command_sender.try_send(command);
+    }
+    if let Some(registration) = &session.registration {
+        registration.update_run(&uuid, &run);
+    }
+    session.set_commands(Some(command_sender.clone()));
+    let _command_channels: Vec<CommandChannel> = introduced.iter()
+        .filter_map(|(url, uuid)| CommandChannel::start(&client, url, uuid, args, command_sender.clone()))
+        .collect();
+    let zap_sender = if args.prevent_telemetry { None } else { Some(ZapSender::start(args, brains, client, urls, command_sender)) };
+
+    let mut log_file: Option<File> = None;
+    if let Some(log_path) = &args.log_to_file {
+        match File::options().append(true).create(true).open(log_path) {
+            Ok(file) => log_file = Some(file),
+            Err(e) => error!("Failed to open log file: {}", e)
+        }
+    }
+
+    let result = loop {
+        let mut process_found = true;
+        if Utc::now() >= next_sample {
+            next_sample += sample_interval;
+            process_found = sampler.sample();
+        }
+
+        let exited = process.has_exited();
+
+        // Always send a final zap so the last messages are not lost
+        if Utc::now() >= next_send || !process_found || exited {
+            next_send += send_interval;
+
+            // taken in one step so lines read while the zap is built are kept for the next one
+            let messages = std::mem::take(&mut *all_message_buffer.lock().unwrap());
+            let messages_to_send = if args.no_remote_logs { None } else { Some(messages.clone()) };
+
+            let eye = zap_sender.as_ref().map(ZapSender::metrics);
+            let mut zap = Zap::from_sample(uuid.clone(), run.clone(), sampler.take(), messages_to_send, custom.and_then(CustomMetrics::take), commands.take_acks(), eye);
+            zap.labels = zap_labels.clone();
+            debug!("Zap: {:?}", zap);
+
+            log_zap(&zap, &mut log_file);
+            session.record_zap(&zap, &messages);
+
+            if let Some(zap_sender) = &zap_sender {
+                zap_sender.send(zap);
+            }
+        }
+
+        if !process_found {
+            debug!("Process with PID {} not found, it may have terminated.", pid);
+            break Ok(());
+        }
+
+        if exited {
+            debug!("Process with PID {} terminated.", pid);
+            break Ok(());
+        }
+
+        // Catch up rather than bursting if a send overran the schedule
+        let now = Utc::now();
+        next_sample = next_sample.max(now);
+        next_send = next_send.max(now);
+
+        // Wait for a while before refreshing stats, or until the brain sends a command
+        let wait = next_sample.min(next_send).signed_duration_since(now).to_std().unwrap_or(Duration::from_secs(0));
+        let wait = session.manager.watchdog_interval().map_or(wait, |interval| wait.min(interval));
+        session.manager.watchdog();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {},
+            Some(command) = command_receiver.recv() => {
+                if let Err(e) = apply_command(commands, command, process.id(), args, &mut send_interval, &mut sample_interval) {
+                    break Err(e);
+                }
+                // send the acknowledgement straight away
+                next_send = Utc::now();
+            },
+        }
+    };
+
+    // Deliver the queued zaps before the exit record, also when a command ends the run
+    if let Some(zap_sender) = zap_sender {
+        session.manager.alive(zap_sender.drain()).await;
+    }
+
+    result
+}
+
+/// Apply a command from the brain, restart and exit commands are returned as errors to end the run.
+fn apply_command(commands: &mut Commands, command: BrainCommand, child_pid: u32, args: &Args, send_interval: &mut Duration, sample_interval: &mut Duration) -> Result<(), BrainWaveError> {
+    match commands.apply(command, child_pid) {
+        CommandEffect::Restart => Err(BrainWaveError::RestartRequired("Restart command received from telemetry server".to_string())),
+        CommandEffect::Exit => Err(BrainWaveError::ExitRequired("Exit command received from telemetry server".to_string())),
+        CommandEffect::SetInterval(interval) => {
+            debug!("Telemetry interval set to {:?} by brain", interval);
+            *send_interval = interval;
+            if args.sample_interval.is_none() {
+                *sample_interval = interval;
+            }
+            Ok(())
+        },
+        CommandEffect::None => Ok(()),
+    }
+}
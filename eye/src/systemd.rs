@@ -0,0 +1,327 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use log::{debug, warn};
+use regex::Regex;
+use crate::types::{Args, RunIdentity};
+#[cfg(unix)]
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Socket of the native journal protocol
+#[cfg(unix)]
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Variables of the systemd protocols, kept from the command so only the eye notifies
+pub const ENVIRONMENT: [&str; 3] = ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"];
+
+/// Connection to the service manager given in `NOTIFY_SOCKET`, for units with `Type=notify`
+struct Notifier {
+    #[cfg(unix)]
+    socket: UnixDatagram,
+    #[cfg(unix)]
+    address: SocketAddr,
+    /// Half the `WatchdogSec=` of the unit, if the eye is the watched process
+    watchdog: Option<Duration>,
+    last_ping: Mutex<Instant>,
+    ready: AtomicBool,
+}
+
+impl Notifier {
+    #[cfg(unix)]
+    fn from_env() -> Option<Self> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        let address = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)
+            },
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return None,
+            None => SocketAddr::from_pathname(&path),
+        };
+
+        let notifier = address.and_then(|address| Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            address,
+            watchdog: watchdog_from_env(),
+            last_ping: Mutex::new(Instant::now()),
+            ready: AtomicBool::new(false),
+        }));
+
+        match notifier {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                warn!("Failed to connect to the service manager at {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from_env() -> Option<Self> {
+        None
+    }
+
+    fn send(&self, state: &str) {
+        #[cfg(unix)]
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            debug!("Failed to notify the service manager: {}", e);
+        }
+    }
+}
+
+fn watchdog_from_env() -> Option<Duration> {
+    let pid = std::env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    if pid.is_some_and(|pid| pid != std::process::id()) {
+        return None;
+    }
+
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec) / 2).filter(|interval| !interval.is_zero())
+}
+
+/// The service manager of the eye, doing nothing outside a `Type=notify` unit.
+///
+/// Clones share the connection, so the ready state and the watchdog timer are kept once per eye.
+#[derive(Clone, Default)]
+pub struct ServiceManager {
+    notifier: Option<Arc<Notifier>>,
+}
+
+impl ServiceManager {
+    pub fn from_env() -> Self {
+        ServiceManager { notifier: Notifier::from_env().map(Arc::new) }
+    }
+
+    /// Tell systemd the service is up, only the first call is sent
+    pub fn ready(&self) {
+        if let Some(notifier) = &self.notifier {
+            if !notifier.ready.swap(true, Ordering::Relaxed) {
+                debug!("Notifying systemd that the command is ready");
+                notifier.send("READY=1");
+            }
+        }
+    }
+
+    /// Status line shown by `systemctl status`
+    pub fn status(&self, status: &str) {
+        if let Some(notifier) = &self.notifier {
+            notifier.send(&format!("STATUS={}", status));
+        }
+    }
+
+    pub fn stopping(&self, status: &str) {
+        if let Some(notifier) = &self.notifier {
+            notifier.send(&format!("STOPPING=1\nSTATUS={}", status));
+        }
+    }
+
+    /// Ping the watchdog if half its timeout passed since the last ping, called from the main loops
+    pub fn watchdog(&self) {
+        let Some(notifier) = &self.notifier else {
+            return;
+        };
+        let Some(interval) = notifier.watchdog else {
+            return;
+        };
+
+        let mut last_ping = notifier.last_ping.lock().unwrap();
+        if last_ping.elapsed() >= interval {
+            notifier.send("WATCHDOG=1");
+            *last_ping = Instant::now();
+        }
+    }
+
+    /// Longest the main loop may wait without pinging the watchdog
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.notifier.as_ref().and_then(|notifier| notifier.watchdog)
+    }
+
+    /// Sleep without starving the watchdog
+    pub async fn sleep(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        loop {
+            self.watchdog();
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return;
+            }
+            tokio::time::sleep(self.watchdog_interval().map_or(left, |interval| left.min(interval))).await;
+        }
+    }
+
+    /// Await a future that may outlast the watchdog timeout, like a send with retries, pinging the watchdog meanwhile
+    pub async fn alive<F: Future>(&self, future: F) -> F::Output {
+        let Some(interval) = self.watchdog_interval() else {
+            return future.await;
+        };
+
+        tokio::pin!(future);
+        // twice per interval, so a late tick never lets a ping slip to the timeout
+        let mut heartbeat = tokio::time::interval(interval / 2);
+        loop {
+            tokio::select! {
+                output = &mut future => return output,
+                _ = heartbeat.tick() => self.watchdog(),
+            }
+        }
+    }
+}
+
+/// Condition a run of the command passes before it is reported ready, every condition given has to hold.
+///
+/// Without `--ready-pattern` and `--ready-after` a run is ready once it is spawned.
+pub struct StartCondition {
+    pattern: Option<Regex>,
+    after: Option<Duration>,
+    started: Instant,
+    matched: AtomicBool,
+    reported: AtomicBool,
+    /// Shown in the status once the run is ready
+    status: String,
+    manager: ServiceManager,
+}
+
+impl StartCondition {
+    pub fn from_args(args: &Args, manager: &ServiceManager, name: &str, restart_index: u32) -> Self {
+        StartCondition {
+            pattern: args.ready_pattern.clone(),
+            after: args.ready_after.map(Duration::from_secs_f64),
+            started: Instant::now(),
+            matched: AtomicBool::new(false),
+            reported: AtomicBool::new(false),
+            status: format!("Running {}, {} restarts", name, restart_index),
+            manager: manager.clone(),
+        }
+    }
+
+    /// Check the condition now and again once `--ready-after` passed, the task is aborted with the run
+    pub fn watch(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        self.check();
+        let start = self.clone();
+        tokio::spawn(async move {
+            if let Some(after) = start.after {
+                tokio::time::sleep(after).await;
+                start.check();
+            }
+        })
+    }
+
+    /// Check an output line against the ready pattern
+    pub fn observe(&self, line: &str) {
+        if self.pattern.as_ref().is_some_and(|pattern| pattern.is_match(line)) {
+            self.matched.store(true, Ordering::Relaxed);
+            self.check();
+        }
+    }
+
+    fn is_met(&self) -> bool {
+        (self.pattern.is_none() || self.matched.load(Ordering::Relaxed))
+            && self.after.is_none_or(|after| self.started.elapsed() >= after)
+    }
+
+    fn check(&self) {
+        if self.is_met() && !self.reported.swap(true, Ordering::Relaxed) {
+            self.manager.ready();
+            self.manager.status(&self.status);
+        }
+    }
+}
+
+/// Writes output lines of the command to the systemd journal with structured fields
+pub struct Journal {
+    #[cfg(unix)]
+    socket: UnixDatagram,
+    /// Fields sent with every line
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Journal {
+    /// Returns `None` if `--journal` is not given or the journal cannot be reached
+    pub fn from_args(args: &Args, uuid: &str, run: &RunIdentity, command: &str) -> Option<Self> {
+        if !args.journal {
+            return None;
+        }
+
+        let mut fields = vec![
+            ("SYSLOG_IDENTIFIER", args.display_name.clone().unwrap_or_else(|| identifier(command))),
+            ("BB_UUID", uuid.to_string()),
+            ("BB_RUN_ID", run.run_id.clone()),
+            ("BB_SESSION_ID", run.session_id.clone()),
+            ("BB_RESTART_INDEX", run.restart_index.to_string()),
+        ];
+        if let Some(display_name) = &args.display_name {
+            fields.push(("BB_DISPLAY_NAME", display_name.clone()));
+        }
+
+        Journal::connect(fields)
+    }
+
+    #[cfg(unix)]
+    fn connect(fields: Vec<(&'static str, String)>) -> Option<Self> {
+        match UnixDatagram::unbound().and_then(|socket| socket.connect(JOURNAL_SOCKET).map(|_| socket)) {
+            Ok(socket) => Some(Journal { socket, fields }),
+            Err(e) => {
+                warn!("Failed to connect to the journal at {}, logging output instead: {}", JOURNAL_SOCKET, e);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn connect(_fields: Vec<(&'static str, String)>) -> Option<Self> {
+        warn!("The journal is only available on Linux, logging output instead");
+        None
+    }
+
+    /// Returns false if the line could not be written, so the caller can log it instead
+    pub fn write(&self, line: &str, error: bool) -> bool {
+        self.send(line, if error { "3" } else { "6" }, if error { "stderr" } else { "stdout" }, None)
+    }
+
+    /// Write a line of a tailed file, with its label or path in `BB_SOURCE`
+    pub fn write_from(&self, source: &str, line: &str) -> bool {
+        self.send(line, "6", "file", Some(source))
+    }
+
+    fn send(&self, line: &str, priority: &str, stream: &str, source: Option<&str>) -> bool {
+        let mut entry = Vec::new();
+        append_field(&mut entry, "MESSAGE", line);
+        append_field(&mut entry, "PRIORITY", priority);
+        append_field(&mut entry, "BB_STREAM", stream);
+        if let Some(source) = source {
+            append_field(&mut entry, "BB_SOURCE", source);
+        }
+        for (name, value) in &self.fields {
+            append_field(&mut entry, name, value);
+        }
+
+        #[cfg(unix)]
+        match self.socket.send(&entry) {
+            Ok(_) => return true,
+            Err(e) => debug!("Failed to write to the journal: {}", e),
+        }
+        false
+    }
+}
+
+/// Values with a newline are sent length-prefixed, as the native protocol requires
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// File name of the executable, as systemd uses for its own output
+fn identifier(command: &str) -> String {
+    let executable = command.split(' ').next().unwrap_or(command);
+    executable.rsplit('/').next().unwrap_or(executable).to_string()
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::registry;
+use crate::types::{Args, MessageBuffer};
+use crate::utils::{push_message, OutputSinks};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Globs are expanded again this often to find new and recreated files, offsets are saved as often
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Most read from one file per poll, so a huge backlog does not starve the other files
+const READ_LIMIT: u64 = 1024 * 1024;
+
+/// Longer lines are split, the rest goes on as the next line, a file without newlines would grow the line forever
+const MAX_LINE: usize = 64 * 1024;
+
+/// A `--tail-file` argument, a path or glob with an optional label naming its lines
+#[derive(Debug, Clone)]
+pub struct TailSpec {
+    pub pattern: String,
+    pub label: Option<String>,
+}
+
+impl TailSpec {
+    /// Parse `path[:label]`, a colon followed by a path separator is part of the path, as in `C:\logs`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (pattern, label) = match spec.rsplit_once(':') {
+            Some((pattern, label)) if !pattern.is_empty() && !label.is_empty() && !label.contains(['/', '\\']) => (pattern, Some(label.to_string())),
+            _ => (spec, None),
+        };
+        if pattern.is_empty() {
+            return Err("expected a path or glob".to_string());
+        }
+        glob::Pattern::new(pattern).map_err(|e| format!("invalid glob `{}`: {}", pattern, e))?;
+        Ok(TailSpec { pattern: pattern.to_string(), label })
+    }
+}
+
+/// Where reading a file stopped, saved across restarts
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Offset {
+    /// Inode of the file, an offset into a rotated file is not carried over to its successor
+    id: u64,
+    offset: u64,
+}
+
+/// A file being followed
+struct TailedFile {
+    source: String,
+    file: File,
+    id: u64,
+    /// Next byte to read
+    offset: u64,
+    /// Start of a line not terminated yet
+    partial: Vec<u8>,
+    /// Byte before the offset, a different one there means the file was rewritten
+    last: Option<u8>,
+}
+
+impl TailedFile {
+    /// Offset of the first byte not delivered as a line
+    fn delivered(&self) -> u64 {
+        self.offset - self.partial.len() as u64
+    }
+}
+
+/// Follows the `--tail-file` files on a thread and feeds their lines into the message buffer.
+///
+/// Rotation by renaming is noticed by the inode behind the path changing, the rest of the old file
+/// is read before the new one. Truncation in place, as by copytruncate, restarts at the beginning.
+pub struct Tailer {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Tailer {
+    /// Returns `None` without `--tail-file`
+    pub fn start(args: &Args, buffer: Arc<Mutex<Vec<MessageBuffer>>>, sinks: OutputSinks) -> Option<Self> {
+        if args.tail_file.is_empty() {
+            return None;
+        }
+
+        let state_path = state_path(args);
+        let mut tail = Tail {
+            specs: args.tail_file.clone(),
+            saved: load(&state_path),
+            state_path,
+            files: HashMap::new(),
+            buffer,
+            sinks,
+            log_buffer_size: args.log_buffer_size,
+            started: true,
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut next_scan = Instant::now();
+            loop {
+                if Instant::now() >= next_scan {
+                    tail.scan();
+                    tail.save();
+                    next_scan = Instant::now() + SCAN_INTERVAL;
+                }
+                tail.poll();
+
+                // read once more after the stop so lines written just before are kept
+                if stopping.load(Ordering::Relaxed) {
+                    tail.poll();
+                    tail.save();
+                    return;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+
+        Some(Tailer { stop, handle })
+    }
+
+    /// Read what is left and save the offsets
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+    }
+}
+
+struct Tail {
+    specs: Vec<TailSpec>,
+    state_path: PathBuf,
+    saved: HashMap<PathBuf, Offset>,
+    files: HashMap<PathBuf, TailedFile>,
+    buffer: Arc<Mutex<Vec<MessageBuffer>>>,
+    sinks: OutputSinks,
+    log_buffer_size: usize,
+    /// Files found by the first scan start at their end unless an offset was saved, later files at their beginning
+    started: bool,
+}
+
+impl Tail {
+    fn scan(&mut self) {
+        for spec in &self.specs {
+            let paths = match glob::glob(&spec.pattern) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    error!("Invalid glob {}: {}", spec.pattern, e);
+                    continue;
+                }
+            };
+
+            for path in paths.filter_map(Result::ok) {
+                if self.files.contains_key(&path) || !path.is_file() {
+                    continue;
+                }
+                let source = spec.label.clone().unwrap_or_else(|| path.to_string_lossy().to_string());
+                if let Some(file) = self.open(&path, source) {
+                    self.files.insert(path, file);
+                }
+            }
+        }
+        self.started = false;
+    }
+
+    fn open(&self, path: &Path, source: String) -> Option<TailedFile> {
+        let (file, metadata) = match File::open(path).and_then(|file| Ok((file.metadata()?, file))) {
+            Ok((metadata, file)) => (file, metadata),
+            Err(e) => {
+                warn!("Failed to open {} for tailing: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        let id = file_id(&metadata);
+        let offset = match self.saved.get(path) {
+            Some(saved) if saved.id == id && saved.offset <= metadata.len() => saved.offset,
+            _ if self.started => metadata.len(),
+            _ => 0,
+        };
+        debug!("Tailing {} from byte {}", path.display(), offset);
+
+        let mut file = TailedFile { source, file, id, offset, partial: Vec::new(), last: None };
+        file.last = byte_before(&mut file);
+        Some(file)
+    }
+
+    fn poll(&mut self) {
+        let paths: Vec<PathBuf> = self.files.keys().cloned().collect();
+        for path in paths {
+            let Some(mut file) = self.files.remove(&path) else {
+                continue;
+            };
+
+            match fs::metadata(&path) {
+                Ok(metadata) if file_id(&metadata) == file.id => {
+                    if metadata.len() < file.offset || was_rewritten(&mut file) {
+                        debug!("{} was truncated, reading from the beginning", path.display());
+                        file.offset = 0;
+                        file.partial.clear();
+                        file.last = None;
+                    }
+                    self.read(&mut file);
+                    self.files.insert(path, file);
+                },
+                // moved away and the new file not created yet, the old one may still be written to
+                Err(_) if is_linked(&file.file) => {
+                    self.read(&mut file);
+                    self.files.insert(path, file);
+                },
+                // rotated or removed, the open handle still reaches the rest of the old file
+                metadata => {
+                    self.read_to_end(&mut file);
+                    self.flush(&mut file);
+                    self.saved.remove(&path);
+                    if metadata.is_ok() {
+                        debug!("{} was rotated, following the new file", path.display());
+                        if let Some(mut new_file) = self.open(&path, file.source.clone()) {
+                            new_file.offset = 0;
+                            new_file.last = None;
+                            self.read(&mut new_file);
+                            self.files.insert(path, new_file);
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    fn read_to_end(&self, file: &mut TailedFile) {
+        while self.read(file) == READ_LIMIT {}
+    }
+
+    /// Read new bytes and emit the complete lines among them, returns the number of bytes read
+    fn read(&self, file: &mut TailedFile) -> u64 {
+        let mut bytes = Vec::new();
+        let read = file.file.seek(SeekFrom::Start(file.offset))
+            .and_then(|_| (&mut file.file).take(READ_LIMIT).read_to_end(&mut bytes));
+        let read = match read {
+            Ok(read) => read as u64,
+            Err(e) => {
+                debug!("Failed to read {}: {}", file.source, e);
+                return 0;
+            }
+        };
+        file.offset += read;
+        if let Some(last) = bytes.last() {
+            file.last = Some(*last);
+        }
+
+        file.partial.extend_from_slice(&bytes);
+        loop {
+            let window = &file.partial[..file.partial.len().min(MAX_LINE + 1)];
+            if let Some(end) = window.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = file.partial.drain(..=end).collect();
+                self.emit(&file.source, &line[..end]);
+            } else if file.partial.len() > MAX_LINE {
+                // cut before a UTF-8 continuation byte, so no character is split
+                let cut = (1..=MAX_LINE).rev().find(|at| file.partial[*at] & 0xC0 != 0x80).unwrap_or(MAX_LINE);
+                let line: Vec<u8> = file.partial.drain(..cut).collect();
+                self.emit(&file.source, &line);
+            } else {
+                break;
+            }
+        }
+        read
+    }
+
+    /// Emit an unterminated line, once its file will not grow any more
+    fn flush(&self, file: &mut TailedFile) {
+        if !file.partial.is_empty() {
+            let line = std::mem::take(&mut file.partial);
+            self.emit(&file.source, &line);
+        }
+    }
+
+    fn emit(&self, source: &str, line: &[u8]) {
+        let line = String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)).to_string();
+        self.sinks.output_from(source, &line);
+        let message = MessageBuffer { message: line, timestamp: Utc::now().timestamp_millis() as u64, error: false, source: Some(source.to_string()) };
+        push_message(&self.buffer, message, self.log_buffer_size, Some(&self.sinks.counts.dropped));
+    }
+
+    fn save(&mut self) {
+        self.saved.retain(|path, _| path.exists());
+        for (path, file) in &self.files {
+            self.saved.insert(path.clone(), Offset { id: file.id, offset: file.delivered() });
+        }
+
+        let result = serde_json::to_vec(&self.saved.iter().map(|(path, offset)| (path.to_string_lossy(), offset)).collect::<HashMap<_, _>>())
+            .map_err(io::Error::from)
+            .and_then(|json| {
+                if let Some(dir) = self.state_path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let temporary = self.state_path.with_extension("json.tmp");
+                fs::write(&temporary, json)?;
+                fs::rename(&temporary, &self.state_path)
+            });
+
+        if let Err(e) = result {
+            warn!("Failed to save tail offsets to {}: {}", self.state_path.display(), e);
+        }
+    }
+}
+
+/// A truncated file that grew past the offset again before this poll has another byte before the offset
+fn was_rewritten(file: &mut TailedFile) -> bool {
+    file.last.is_some_and(|last| byte_before(file).is_some_and(|byte| byte != last))
+}
+
+fn byte_before(file: &mut TailedFile) -> Option<u8> {
+    let mut byte = [0];
+    let position = file.offset.checked_sub(1)?;
+    file.file.seek(SeekFrom::Start(position)).and_then(|_| file.file.read_exact(&mut byte)).ok()?;
+    Some(byte[0])
+}
+
+/// `--tail-state`, or a file in the runtime directory named after what this eye tails
+fn state_path(args: &Args) -> PathBuf {
+    if let Some(path) = &args.tail_state {
+        return PathBuf::from(path);
+    }
+
+    let mut hasher = Sha256::new();
+    for spec in &args.tail_file {
+        hasher.update(spec.pattern.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(args.display_name.as_deref().unwrap_or_default().as_bytes());
+    let hash = format!("{:x}", hasher.finalize());
+    registry::runtime_dir(args).join(format!("tail-{}.json", &hash[..16]))
+}
+
+fn load(path: &Path) -> HashMap<PathBuf, Offset> {
+    let json = match fs::read(path) {
+        Ok(json) => json,
+        Err(_) => return HashMap::new(),
+    };
+
+    match serde_json::from_slice::<HashMap<String, Offset>>(&json) {
+        Ok(saved) => saved.into_iter().map(|(path, offset)| (PathBuf::from(path), offset)).collect(),
+        Err(e) => {
+            warn!("Ignoring unreadable tail offsets in {}: {}", path.display(), e);
+            HashMap::new()
+        }
+    }
+}
+
+/// False once the file was deleted rather than renamed
+#[cfg(unix)]
+fn is_linked(file: &File) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    file.metadata().is_ok_and(|metadata| metadata.nlink() > 0)
+}
+
+#[cfg(not(unix))]
+fn is_linked(_file: &File) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+/// Without inodes rotation by renaming is only noticed once the new file is shorter than the offset
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> u64 {
+    0
+}
@@ -1,82 +1,409 @@
-use reqwest::Client;
-use once_cell::sync::Lazy;
-use log::{debug, error};
-use crate::types::BrainWaveError;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use reqwest::{Client, Response, StatusCode};
+use reqwest::header::RETRY_AFTER;
+use log::{debug, error, warn};
+use crate::circuit::Circuits;
+use crate::types::{Args, BrainWaveError, BrainCommand, CircuitState, CommandKind, Encoding, Endpoint, ENCODINGS_HEADER, PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER};
 
-static TELEMETRY_ENDPOINT: Lazy<String> = Lazy::new(|| {
-    std::env::var("TELEMETRY_ENDPOINT").unwrap_or_else(|_| "http://localhost:8000/telemetry".to_string())
-});
+/// First wait between retries, doubled on every attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
 
-static CLIENT: Lazy<Client> = Lazy::new(|| Client::new());
+/// Longest wait before a retry, a longer `Retry-After` opens the circuit instead
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
 
-static SYSTEM_START_TIME: Lazy<std::sync::Mutex<f64>> = Lazy::new(|| std::sync::Mutex::new(0.0));
+/// Settings of a [`TelemetryClient`], the defaults match those of `bb_eye`
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    /// Base URL of the telemetry server, payloads go to `{endpoint}/zap` and so on
+    pub endpoint: String,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    /// Retries of a failed request
+    pub max_retries: u32,
+    /// Consecutive failed requests before a server is paused
+    pub circuit_failures: u32,
+    /// Time between probes of a paused server
+    pub circuit_probe_interval: Duration,
+    /// Hold telemetry back for this long after the start of every run
+    pub delay: Duration,
+    /// Preferred encoding of payloads, servers that do not accept it get msgpack
+    pub encoding: Encoding,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            endpoint: std::env::var("TELEMETRY_ENDPOINT").unwrap_or_else(|_| "http://localhost:8000/telemetry".to_string()),
+            connect_timeout: Duration::from_secs(2),
+            request_timeout: Duration::from_secs(2),
+            max_retries: 3,
+            circuit_failures: 5,
+            circuit_probe_interval: Duration::from_secs(30),
+            delay: Duration::from_secs_f64(std::env::var("TELEMETRY_DELAY").ok().and_then(|delay| delay.parse().ok()).unwrap_or(0.0)),
+            encoding: Encoding::Msgpack,
+        }
+    }
+}
+
+impl TelemetryConfig {
+    pub fn from_args(args: &Args) -> Self {
+        let defaults = TelemetryConfig::default();
+
+        TelemetryConfig {
+            endpoint: args.telemetry_endpoint.first().cloned().unwrap_or(defaults.endpoint),
+            connect_timeout: Duration::from_secs_f64(args.connect_timeout),
+            request_timeout: Duration::from_secs_f64(args.request_timeout),
+            max_retries: args.max_retries,
+            circuit_failures: args.circuit_failures,
+            circuit_probe_interval: Duration::from_secs_f64(args.circuit_probe_interval),
+            delay: if args.telemetry_delay > 0.0 { Duration::from_secs_f64(args.telemetry_delay) } else { defaults.delay },
+            encoding: args.encoding,
+        }
+    }
+}
 
-static TELEMETRY_DELAY: Lazy<std::sync::Mutex<f64>> = Lazy::new(|| std::sync::Mutex::new(
-    std::env::var("TELEMETRY_DELAY").unwrap_or("0.0".to_string()).parse().unwrap_or(0.0)
-));
+/// What a telemetry server announced in the headers of its responses
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Handshake {
+    /// Protocol version of the server, `None` for brains that predate versioning
+    pub protocol_version: Option<u32>,
+    /// Encodings the server accepts, `None` if it did not say
+    pub encodings: Option<Vec<Encoding>>,
+}
+
+impl Handshake {
+    fn from_response(response: &Response) -> Option<Self> {
+        let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+        let protocol_version = header(PROTOCOL_VERSION_HEADER).and_then(|version| version.trim().parse().ok());
+        let encodings = header(ENCODINGS_HEADER).map(|encodings| encodings.split(',').filter_map(Encoding::from_name).collect());
+
+        if protocol_version.is_none() && encodings.is_none() {
+            return None;
+        }
+        Some(Handshake { protocol_version, encodings })
+    }
 
-static TELEMETRY_DELAY_NEEDED: Lazy<std::sync::Mutex<bool>> = Lazy::new(|| std::sync::Mutex::new(false));
+    /// The preferred encoding if the server accepts it, msgpack otherwise
+    pub fn encoding(&self, preferred: Encoding) -> Encoding {
+        match &self.encodings {
+            Some(encodings) if !encodings.contains(&preferred) => Encoding::Msgpack,
+            _ => preferred,
+        }
+    }
+}
 
-pub fn reset_system_start_time() {
-    *SYSTEM_START_TIME.lock().unwrap() = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs_f64();
-    *TELEMETRY_DELAY_NEEDED.lock().unwrap() = true;
+/// Connection to one or more telemetry servers.
+///
+/// Clones share the HTTP connections, circuit breakers, handshakes and failure count.
+#[derive(Clone)]
+pub struct TelemetryClient {
+    inner: Arc<ClientInner>,
 }
 
-pub fn set_telemetry_delay(delay: f64) {
-    debug!("Setting telemetry delay to {}", delay);
-    *TELEMETRY_DELAY.lock().unwrap() = delay;
+struct ClientInner {
+    http: Client,
+    config: TelemetryConfig,
+    circuits: Circuits,
+    /// Start of the current run, while the delay is still running
+    delay_started: Mutex<Option<Instant>>,
+    /// Requests that failed after their retries or were skipped by the circuit
+    failures: AtomicU64,
+    /// Last handshake per base URL
+    handshakes: Mutex<HashMap<String, Handshake>>,
 }
 
-pub async fn send_telemetry(endpoint: &str, data: Vec<u8>, endpoint_override: Option<String>) -> Result<String, BrainWaveError> {
-    let remote_endpoint = endpoint_override.unwrap_or(TELEMETRY_ENDPOINT.to_string()) + "/" + endpoint;
+impl TelemetryClient {
+    pub fn new(config: TelemetryConfig) -> Self {
+        let http = Client::builder().connect_timeout(config.connect_timeout).build().unwrap_or_else(|e| {
+            error!("Failed to configure telemetry client: {}", e);
+            Client::new()
+        });
+
+        TelemetryClient {
+            inner: Arc::new(ClientInner {
+                http,
+                circuits: Circuits::new(config.circuit_failures, config.circuit_probe_interval),
+                config,
+                delay_started: Mutex::new(None),
+                failures: AtomicU64::new(0),
+                handshakes: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        TelemetryClient::new(TelemetryConfig::from_args(args))
+    }
+
+    /// Base URL of the telemetry server, circuits are kept per base URL
+    pub fn url(&self, endpoint_override: Option<&str>) -> String {
+        endpoint_override.unwrap_or(&self.inner.config.endpoint).to_string()
+    }
+
+    /// Requests that failed since the client was created
+    pub fn failures(&self) -> u64 {
+        self.inner.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn circuit_state(&self, url: &str) -> CircuitState {
+        self.inner.circuits.state(url)
+    }
+
+    /// True unless the last request to `url` failed
+    pub fn is_healthy(&self, url: &str) -> bool {
+        self.inner.circuits.is_healthy(url)
+    }
+
+    /// What the server at `url` announced, `None` before its first response
+    pub fn handshake(&self, url: &str) -> Option<Handshake> {
+        self.inner.handshakes.lock().unwrap().get(url).cloned()
+    }
+
+    /// Encoding of payloads sent to `url`, the configured one unless the server declined it
+    pub fn encoding(&self, url: &str) -> Encoding {
+        let preferred = self.inner.config.encoding;
+        self.handshake(url).map_or(preferred, |handshake| handshake.encoding(preferred))
+    }
+
+    /// Start the telemetry delay again, called at the start of every run
+    pub fn reset_delay(&self) {
+        *self.inner.delay_started.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Send a payload to the server at `url`, or the configured one.
+    ///
+    /// Returns the body of a `201` response, the uuid for an introduction. Commands in the
+    /// response are returned as [`BrainWaveError::CommandReceived`]. A server that cannot be
+    /// reached is logged and answers with an empty body, so telemetry never stops a run.
+    pub async fn send<T: Endpoint>(&self, payload: &T, url: Option<&str>) -> Result<String, BrainWaveError> {
+        let mut encoding = self.encoding(&self.url(url));
+        let result = loop {
+            let data = match payload.encode(encoding) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Failed to encode {}: {}", payload.endpoint(), e);
+                    return Ok("".to_string());
+                }
+            };
+
+            match self.post(payload.endpoint(), data, encoding, payload.idempotent(), url).await {
+                // the server refused the payload unread, so sending it again is safe
+                Err(BrainWaveError::UnsupportedEncoding(_)) if encoding != Encoding::Msgpack => encoding = Encoding::Msgpack,
+                result => break result,
+            }
+        };
+
+        match result {
+            Ok(response) => Ok(response),
+            Err(BrainWaveError::ReqwestError(e)) => {
+                error!("Telemetry request failed: {}", e);
+                Ok("".to_string()) // Continue execution even if telemetry fails
+            },
+            Err(e) => Err(e), // Propagate other errors
+        }
+    }
+
+    /// Wait out what is left of the `--delay` before the first telemetry request
+    pub async fn wait_for_delay(&self) {
+        let delay = self.inner.config.delay;
+        if delay.is_zero() {
+            return;
+        }
+
+        let started = *self.inner.delay_started.lock().unwrap();
+        let Some(started) = started else {
+            return;
+        };
+
+        let elapsed = started.elapsed();
+        debug!("Start time: {:?}", elapsed);
+        if elapsed < delay {
+            debug!("Delaying telemetry for {:?}", delay - elapsed);
+            tokio::time::sleep(delay - elapsed).await;
+        } else {
+            *self.inner.delay_started.lock().unwrap() = None;
+        }
+    }
+
+    /// Send a payload, retrying failed attempts with exponential backoff.
+    ///
+    /// Payloads that are not idempotent are only retried when the connection could not be
+    /// made, so the brain never sees them twice. Requests are skipped while the circuit of
+    /// the telemetry server is open.
+    async fn post(&self, endpoint: &str, data: Vec<u8>, encoding: Encoding, idempotent: bool, url: Option<&str>) -> Result<String, BrainWaveError> {
+        let base_url = self.url(url);
+        let remote_endpoint = format!("{}/{}", base_url, endpoint);
+        let circuits = &self.inner.circuits;
+
+        self.wait_for_delay().await;
+
+        if !circuits.allow(&base_url) {
+            debug!("Circuit for {} is open, skipping {}", base_url, endpoint);
+            self.inner.failures.fetch_add(1, Ordering::Relaxed);
+            return Ok("".to_string());
+        }
+
+        debug!("Sending telemetry to {}", remote_endpoint);
+
+        let max_retries = self.inner.config.max_retries;
+        let mut attempt = 0;
+        let response = loop {
+            let result = self.inner.http
+                .post(&remote_endpoint)
+                .header("Content-Type", encoding.content_type())
+                .header(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION)
+                .body(data.clone())
+                .timeout(self.inner.config.request_timeout)
+                .send()
+                .await;
+
+            let retry_delay = match &result {
+                Ok(response) if is_overloaded(response.status()) => Some(retry_after(response).unwrap_or(backoff(attempt))),
+                Ok(response) if response.status().is_server_error() && idempotent => Some(backoff(attempt)),
+                Err(e) if idempotent || e.is_connect() => Some(backoff(attempt)),
+                _ => None,
+            };
+
+            match retry_delay {
+                Some(delay) if delay > MAX_RETRY_DELAY => {
+                    circuits.open_until(&base_url, Instant::now() + delay);
+                    break result;
+                },
+                Some(delay) if attempt < max_retries => {
+                    attempt += 1;
+                    debug!("Telemetry request to {} failed, retry {} of {} in {:?}", remote_endpoint, attempt, max_retries, delay);
+                    tokio::time::sleep(delay).await;
+                },
+                _ => break result,
+            }
+        };
+
+        match &response {
+            Ok(response) if !is_overloaded(response.status()) && !response.status().is_server_error() => circuits.record_success(&base_url),
+            _ => circuits.record_failure(&base_url),
+        }
+
+        if !response.as_ref().is_ok_and(|response| response.status().is_success()) {
+            self.inner.failures.fetch_add(1, Ordering::Relaxed);
+        }
 
-    let delay = *TELEMETRY_DELAY.lock().unwrap();
-    if delay > 0.0 && *TELEMETRY_DELAY_NEEDED.lock().unwrap() {
-        let start_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f64() - *SYSTEM_START_TIME.lock().unwrap();
+        let response = response?;
+        self.record_handshake(&base_url, &response);
+        let status = response.status();
+        if status == StatusCode::UNSUPPORTED_MEDIA_TYPE && encoding != Encoding::Msgpack {
+            warn!("Telemetry server {} refused {}, sending msgpack", base_url, encoding.name());
+            self.inner.handshakes.lock().unwrap().entry(base_url).or_default().encodings.get_or_insert_with(|| vec![Encoding::Msgpack]).retain(|accepted| *accepted != encoding);
+            return Err(BrainWaveError::UnsupportedEncoding(encoding.name().to_string()));
+        }
+        if !status.is_success() {
+            error!("Ignoring failed telemetry request with status: {}", status);
+            return Ok("".to_string());
+        }
+
+        let is_msgpack = response.headers()
+            .get("Content-Type")
+            .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/msgpack"));
+
+        if status == 200 && is_msgpack {
+            let body = response.bytes().await?;
+            match rmp_serde::from_slice::<BrainCommand>(&body) {
+                Ok(command) => return Err(BrainWaveError::CommandReceived(command)),
+                Err(e) => {
+                    error!("Telemetry server returned an undecodable command: {}", e);
+                    return Ok("".to_string());
+                }
+            }
+        }
 
-        debug!("Start time: {}", start_time);
-        if start_time < delay {
-            debug!("Delaying telemetry for {} seconds", delay-start_time);
-            tokio::time::sleep(std::time::Duration::from_secs_f64(delay-start_time)).await;
+        let body = response.text().await?;
+        if status == 200 {
+            // plain text commands from older brains carry no id and are not acknowledged
+            let kind = match body.as_str() {
+                "restart" => CommandKind::Restart,
+                "exit" => CommandKind::Exit,
+                _ => {
+                    debug!("Telemetry server returned unknown command: {}", body);
+                    return Ok("".to_string());
+                }
+            };
+            return Err(BrainWaveError::CommandReceived(BrainCommand { id: "".to_string(), kind, arguments: Default::default(), issued_at: 0 }));
         }
-        else {
-            *TELEMETRY_DELAY_NEEDED.lock().unwrap() = false;
+
+        if status == 201 {
+            return Ok(body);
         }
+
+        Ok("".to_string())
     }
 
-    debug!("Sending telemetry to {}", remote_endpoint);
+    /// Long-poll the brain for the next command for `uuid`, `None` if none arrived within `wait`
+    pub async fn poll_command(&self, uuid: &str, wait: Duration, url: Option<&str>) -> Result<Option<BrainCommand>, BrainWaveError> {
+        let remote_endpoint = self.url(url) + "/commands/" + uuid;
+
+        let response = self.inner.http
+            .get(remote_endpoint)
+            .header(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION)
+            .query(&[("wait", wait.as_secs_f64())])
+            .timeout(wait + Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        if response.status() != 200 {
+            return Ok(None);
+        }
 
-    let response = CLIENT
-        .post(remote_endpoint)
-        .header("Content-Type", "application/msgpack")
-        .body(data)
-        .timeout(std::time::Duration::from_secs(2))
-        .send()
-        .await?;
-    let status = response.status();
-    if !status.is_success() {
-        error!("Ignoring failed telemetry request with status: {}", status);
-        return Ok("".to_string());
+        let body = response.bytes().await?;
+        match rmp_serde::from_slice::<BrainCommand>(&body) {
+            Ok(command) => Ok(Some(command)),
+            Err(e) => {
+                error!("Telemetry server pushed an undecodable command: {}", e);
+                Ok(None)
+            }
+        }
     }
 
-    let body = response.text().await?;  
-    if status == 200 {
-        match body.as_str() {
-            "restart" => return Err(BrainWaveError::RestartRequired("Restart command received from telemetry server".to_string()).into()),
-            "exit" => return Err(BrainWaveError::ExitRequired("Exit command received from telemetry server".to_string()).into()),
-            _ => debug!("Telemetry server returned unknown command: {}", body),
+    /// Keep what the server announced, a changed protocol version is logged once
+    fn record_handshake(&self, url: &str, response: &Response) {
+        let Some(handshake) = Handshake::from_response(response) else {
+            return;
+        };
+
+        let mut handshakes = self.inner.handshakes.lock().unwrap();
+        if handshakes.get(url) == Some(&handshake) {
+            return;
+        }
+
+        match handshake.protocol_version {
+            Some(version) if version != PROTOCOL_VERSION => warn!("Telemetry server {} speaks protocol version {}, this eye speaks {}", url, version, PROTOCOL_VERSION),
+            _ => debug!("Telemetry server {} announced {:?}", url, handshake),
+        }
+        let encoding = handshake.encoding(self.inner.config.encoding);
+        if encoding != self.inner.config.encoding {
+            warn!("Telemetry server {} does not accept {}, sending {}", url, self.inner.config.encoding.name(), encoding.name());
         }
+        handshakes.insert(url.to_string(), handshake);
     }
+}
+
+fn is_overloaded(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+}
+
+fn backoff(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_RETRY_DELAY)
+}
 
-    if status == 201 {
-        return Ok(body);
+/// Parse a `Retry-After` header given in seconds or as an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
     }
 
-    Ok("".to_string())
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().unwrap_or(Duration::ZERO))
 }
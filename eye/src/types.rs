@@ -1,15 +1,24 @@
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use schemars::JsonSchema;
 use chrono::Utc;
-use clap::Parser;
-use sysinfo::Process;
-use crate::utils::{get_current_user, get_hostname, get_folder_size};
+use clap::{Parser, Subcommand, ValueEnum};
+use crate::utils::{get_current_user, get_hostname};
 use std::error::Error;
-use crate::telemetry::send_telemetry;
-use log::{error, debug};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::metrics::Sample;
+use crate::data::parse_scan_interval;
+use crate::labels::parse_label;
+use crate::query::parse_since;
+use crate::tail::TailSpec;
+use regex::Regex;
+use ulid::Ulid;
 
 #[derive(Debug)]
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
     /// Restart the command if it exits
     #[arg(short = 'r', long, default_value_t = false)]
@@ -31,18 +40,38 @@ pub struct Args {
     #[arg(short = 'c', long, default_value_t = false)]
     pub no_remote_logs: bool,
 
-    /// Track data folder size
+    /// Track data folder or file size, repeat for multiple paths
     #[arg(short = 'd', long)]
-    pub data_folder: Option<String>,
+    pub data_folder: Vec<String>,
 
-    /// Telemetry endpoint
-    #[arg(short = 'e', long)]
-    pub telemetry_endpoint: Option<String>,
+    /// Seconds between full scans of the data folders
+    #[arg(long, default_value_t = 10.0, value_parser = parse_scan_interval)]
+    pub data_scan_interval: f64,
+
+    /// Watch the data folders for changes and adjust sizes between scans
+    #[arg(long, default_value_t = false)]
+    pub data_watch: bool,
+
+    /// Telemetry endpoint, repeat for multiple brains
+    #[arg(short = 'e', long, global = true, value_delimiter = ',')]
+    pub telemetry_endpoint: Vec<String>,
+
+    /// How payloads are spread over multiple telemetry endpoints
+    #[arg(long, global = true, value_enum, default_value_t = EndpointMode::Failover)]
+    pub endpoint_mode: EndpointMode,
+
+    /// Encoding of payloads, brains that do not accept it get msgpack
+    #[arg(long, global = true, value_enum, default_value_t = Encoding::Msgpack)]
+    pub encoding: Encoding,
 
     /// Telemetry Interval
     #[arg(short = 'i', long, default_value_t = 1.0)]
     pub telemetry_interval: f64,
 
+    /// Metrics sampling interval, defaults to the telemetry interval
+    #[arg(long)]
+    pub sample_interval: Option<f64>,
+
     /// Telemetry log buffer size
     #[arg(short = 'b', long, default_value_t = 50)]
     pub log_buffer_size: usize,
@@ -71,9 +100,336 @@ pub struct Args {
     #[arg(short = 'D', long)]
     pub display_name: Option<String>,
 
+    /// Label as key=value, ${VAR} is taken from the environment, repeat for multiple labels
+    #[arg(long, value_parser = parse_label)]
+    pub label: Vec<(String, String)>,
+
+    /// File of key=value labels, one per line
+    #[arg(long)]
+    pub label_file: Vec<String>,
+
+    /// Send the labels with every zap as well as the introduction
+    #[arg(long)]
+    pub zap_labels: bool,
+
+    /// Seconds to wait for a connection to the telemetry server
+    #[arg(long, default_value_t = 2.0)]
+    pub connect_timeout: f64,
+
+    /// Seconds to wait for a telemetry request to complete
+    #[arg(long, default_value_t = 2.0)]
+    pub request_timeout: f64,
+
+    /// Retries of a failed telemetry request
+    #[arg(long, default_value_t = 3)]
+    pub max_retries: u32,
+
+    /// Consecutive failed requests before telemetry is paused
+    #[arg(long, default_value_t = 5)]
+    pub circuit_failures: u32,
+
+    /// Seconds between probes of a telemetry server after failures
+    #[arg(long, default_value_t = 30.0)]
+    pub circuit_probe_interval: f64,
+
+    /// Zaps waiting to be sent before new ones are dropped
+    #[arg(long, default_value_t = 60)]
+    pub send_queue_size: usize,
+
+    /// Hold a connection open to the brain so commands arrive immediately
+    #[arg(long, default_value_t = false)]
+    pub push_commands: bool,
+
+    /// Process metrics level
+    #[arg(long, value_enum, default_value_t = MetricsLevel::Basic)]
+    pub metrics_level: MetricsLevel,
+
+    /// Listen for StatsD metrics from the command on this localhost UDP port, 0 picks a free port
+    #[arg(long)]
+    pub statsd_port: Option<u16>,
+
+    /// Accept StatsD metrics from the command on a Unix socket passed in BB_EYE_METRICS_SOCKET
+    #[arg(long, default_value_t = false)]
+    pub metrics_socket: bool,
+
+    /// Treat stdout lines starting with this prefix as StatsD metrics
+    #[arg(long)]
+    pub metrics_prefix: Option<String>,
+
+    /// Report host-level metrics alongside process metrics (comma separated)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub host_metrics: Vec<HostMetric>,
+
+    /// Keep introductions, zaps, logs and exits in a local SQLite database
+    #[arg(long, global = true, env = "BB_EYE_HISTORY")]
+    pub history: Option<String>,
+
+    /// Days of history to keep
+    #[arg(long, default_value_t = 7.0)]
+    pub history_retention: f64,
+
+    /// Hours after which stored zaps are thinned to one per minute
+    #[arg(long, default_value_t = 24.0)]
+    pub history_downsample_after: f64,
+
+    /// Write every payload sent to the brains to this file, for `replay`
+    #[arg(long)]
+    pub record: Option<String>,
+
+    /// Report readiness to systemd once an output line matches this regular expression
+    #[arg(long, value_parser = Regex::new)]
+    pub ready_pattern: Option<Regex>,
+
+    /// Report readiness to systemd once the command ran this many seconds
+    #[arg(long)]
+    pub ready_after: Option<f64>,
+
+    /// Write the command's output to the systemd journal with structured fields instead of logging it
+    #[arg(long, default_value_t = false)]
+    pub journal: bool,
+
+    /// Run in the background, printing the pid of the eye and writing its output to --daemon-log
+    #[arg(long, default_value_t = false)]
+    pub detach: bool,
+
+    /// Write the pid of the eye to this file while it runs, refusing to start if a live eye holds it
+    #[arg(long)]
+    pub pidfile: Option<String>,
+
+    /// File for the output of a detached eye, a new file in the runtime directory if not given
+    #[arg(long)]
+    pub daemon_log: Option<String>,
+
+    /// Directory where running eyes register themselves, for `list`
+    #[arg(long, global = true, env = "BB_EYE_RUNTIME_DIR")]
+    pub runtime_dir: Option<String>,
+
+    /// Follow a log file as path[:label] and send its lines with the output, globs allowed, repeat for multiple files
+    #[arg(long, value_parser = TailSpec::parse)]
+    pub tail_file: Vec<TailSpec>,
+
+    /// File keeping the read offsets of tailed files across restarts, in the runtime directory if not given
+    #[arg(long)]
+    pub tail_state: Option<String>,
+
+    /// Monitor this running process instead of running a command, see `attach`
+    #[arg(long, conflicts_with = "command")]
+    pub pid: Option<u32>,
+
+    /// Monitor the running process whose name or command line matches this regular expression, see `attach`
+    #[arg(long = "match", value_parser = Regex::new, conflicts_with_all = ["command", "pid"])]
+    pub match_process: Option<Regex>,
+
+    /// Attach to the next process matching --match once the attached one exits
+    #[arg(long, default_value_t = false, requires = "match_process")]
+    pub reattach: bool,
+
     /// Command to run
-    #[arg(required = true, allow_hyphen_values = true)]
+    #[arg(required_unless_present_any = ["pid", "match_process"], allow_hyphen_values = true)]
     pub command: Vec<String>,
+
+    #[command(subcommand)]
+    pub query: Option<Query>,
+}
+
+/// Subcommands run instead of a command
+#[derive(Debug, Subcommand)]
+// the doc comment would become the description of bb_eye in --help
+#[command(about = None, long_about = None)]
+pub enum Query {
+    /// List recent runs
+    History {
+        /// Number of runs to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        /// Only runs whose id starts with this
+        #[arg(long)]
+        run: Option<String>,
+    },
+    /// Print stored output lines
+    Logs {
+        /// Only lines newer than this age (30s, 15m, 2h, 7d) or RFC 3339 time
+        #[arg(long, value_parser = parse_since)]
+        since: Option<i64>,
+
+        /// Only lines matching this regular expression
+        #[arg(long, value_parser = Regex::new)]
+        grep: Option<Regex>,
+
+        /// Only lines of runs whose id starts with this
+        #[arg(long)]
+        run: Option<String>,
+    },
+    /// Export stored metrics
+    Metrics {
+        /// Only zaps newer than this age (30s, 15m, 2h, 7d) or RFC 3339 time
+        #[arg(long, value_parser = parse_since)]
+        since: Option<i64>,
+
+        /// Only zaps of runs whose id starts with this
+        #[arg(long)]
+        run: Option<String>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = MetricsFormat::Csv)]
+        format: MetricsFormat,
+    },
+    /// Send a file written with --record to the brains
+    Replay {
+        /// Recording to send
+        file: String,
+
+        /// Send payloads back to back instead of with their recorded spacing
+        #[arg(long)]
+        fast: bool,
+    },
+    /// Serve a stand-in brain that keeps payloads in memory, for development and tests
+    BrainMock {
+        /// Port on localhost, 0 picks a free port
+        #[arg(long, default_value_t = 8000)]
+        port: u16,
+
+        /// JSON list of rules scripting uuids, commands, errors and delays
+        #[arg(long)]
+        script: Option<String>,
+
+        /// Encodings to accept (comma separated), all if not given
+        #[arg(long, value_enum, value_delimiter = ',')]
+        accept: Vec<Encoding>,
+    },
+    /// Run a command, for options after the subcommand such as `bb_eye run --detach -- cmd`
+    Run {
+        /// Options and command, as given without `run`
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Monitor a process the eye did not start, given with --pid or --match, such as `bb_eye attach --match nginx`
+    Attach {
+        /// Options, as given without `attach`
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// List the eyes running on this host and remove entries of eyes that are gone
+    List,
+    /// Print the JSON Schema of the wire protocol
+    Schema {
+        /// Write one schema file per payload into this directory instead
+        #[arg(long)]
+        out_dir: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MetricsFormat {
+    /// Memory, CPU and disk per zap
+    Csv,
+    /// Every stored zap as a JSON line
+    Json,
+}
+
+/// Version of the wire protocol, raised on changes older brains cannot read.
+///
+/// 2 added payload `sequence` numbers and idempotency keys, and the `source` of tailed lines.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Request and response header carrying the protocol version of the sender
+pub const PROTOCOL_VERSION_HEADER: &str = "X-BB-Protocol-Version";
+
+/// Response header listing the encodings a brain accepts, comma separated
+pub const ENCODINGS_HEADER: &str = "X-BB-Encodings";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Msgpack,
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 3] = [Encoding::Msgpack, Encoding::Json, Encoding::Cbor];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Msgpack => "msgpack",
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Msgpack => "application/msgpack",
+            Encoding::Json => "application/json",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    /// Encoding of a `Content-Type` header, parameters such as `charset` are ignored
+    pub fn from_content_type(content_type: &str) -> Option<Encoding> {
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        Encoding::ALL.into_iter().find(|encoding| encoding.content_type().eq_ignore_ascii_case(media_type))
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        Encoding::ALL.into_iter().find(|encoding| encoding.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// Maps are encoded with their field names in every encoding
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        Ok(match self {
+            Encoding::Msgpack => rmp_serde::to_vec_named(value)?,
+            Encoding::Json => serde_json::to_vec(value)?,
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes)?;
+                bytes
+            },
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Box<dyn Error + Send + Sync>> {
+        Ok(match self {
+            Encoding::Msgpack => rmp_serde::from_slice(bytes)?,
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            Encoding::Cbor => ciborium::from_reader(bytes)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EndpointMode {
+    /// Send to the first healthy endpoint, in the order given
+    Failover,
+    /// Send every payload to every endpoint
+    FanOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MetricsLevel {
+    /// Memory and CPU only
+    Basic,
+    /// Also I/O, virtual memory, threads, file descriptors, context switches, status and start time
+    Extended,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HostMetric {
+    /// Every host metric below
+    All,
+    /// Total/used memory and swap
+    Memory,
+    /// 1, 5 and 15 minute load average
+    Load,
+    /// Per-core CPU usage
+    Cpu,
+    /// Host uptime
+    Uptime,
+    /// Space per mounted disk
+    Disks,
+    /// Throughput per network interface
+    Network,
 }
 
 #[derive(Debug)]
@@ -81,6 +437,8 @@ pub enum BrainWaveError {
     RestartRequired(String),
     ExitRequired(String),
     ReqwestError(String),
+    CommandReceived(BrainCommand),
+    UnsupportedEncoding(String),
 }
 
 impl std::fmt::Display for BrainWaveError {
@@ -89,6 +447,8 @@ impl std::fmt::Display for BrainWaveError {
             BrainWaveError::RestartRequired(msg) => write!(f, "Process restart required: {}", msg),
             BrainWaveError::ExitRequired(msg) => write!(f, "Process exit required: {}", msg), 
             BrainWaveError::ReqwestError(msg) => write!(f, "Request error occurred: {}", msg),
+            BrainWaveError::CommandReceived(command) => write!(f, "Command received: {:?}", command),
+            BrainWaveError::UnsupportedEncoding(encoding) => write!(f, "Encoding not accepted: {}", encoding),
         }
     }
 }
@@ -101,64 +461,316 @@ impl From<reqwest::Error> for BrainWaveError {
 }
 
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Command envelope sent by the brain in the response to a telemetry request
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct BrainCommand {
+    pub id: String,
+    pub kind: CommandKind,
+    #[serde(default)]
+    pub arguments: HashMap<String, String>,
+    pub issued_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommandKind {
+    Restart,
+    Exit,
+    Signal,
+    Pause,
+    Resume,
+    SetInterval,
+    StopAfterDrain,
+    #[serde(other)]
+    Unknown,
+}
+
+/// Outcome of a command, sent back to the brain on the next zap or exit
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct CommandAck {
+    pub id: String,
+    pub outcome: CommandOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub time: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandOutcome {
+    Applied,
+    Failed,
+    Rejected,
+    Duplicate,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct MessageBuffer {
     pub message: String,
     pub timestamp: u64,
-    pub error: bool
+    pub error: bool,
+    /// Label or path of the tailed file the line was read from, none for the command's output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Ids generated by the eye, linking the payloads of a run and the runs of one eye
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RunIdentity {
+    /// Sortable id of this run of the command
+    pub run_id: String,
+    /// Shared by every run of one eye
+    pub session_id: String,
+    /// Number of runs before this one in the session
+    pub restart_index: u32,
+    /// Sequence number of the next payload of the run, shared by clones
+    #[serde(skip)]
+    next_sequence: Arc<AtomicU64>,
+}
+
+impl RunIdentity {
+    pub fn new(session_id: &str, restart_index: u32) -> Self {
+        RunIdentity { run_id: Ulid::new().to_string(), session_id: session_id.to_string(), restart_index, next_sequence: Default::default() }
+    }
+
+    /// Take the next sequence number and the idempotency key of the payload carrying it
+    pub fn next_sequence(&self) -> (u64, String) {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        (sequence, format!("{}:{}", self.run_id, sequence))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Zap {
+    /// Missing from payloads of eyes before the protocol was versioned
+    #[serde(default)]
+    pub protocol_version: u32,
     pub uuid: String,
+    #[serde(flatten)]
+    pub run: RunIdentity,
+    /// Counts every payload of the run from 0, a gap is a payload the brain never received
+    #[serde(default)]
+    pub sequence: u64,
+    /// Same for every retry and replay of the payload, so the brain can drop duplicates
+    #[serde(default)]
+    pub idempotency_key: String,
     pub memory: f64,
     pub cpu: f64,
     pub time: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub disk: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Vec<DataUsage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub messages: Option<Vec<MessageBuffer>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extended: Option<ExtendedMetrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostMetrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom: Option<HashMap<String, CustomMetric>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acks: Option<Vec<CommandAck>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eye: Option<EyeMetrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<HashMap<String, String>>,
+}
+
+/// Health of the eye's own telemetry pipeline since the previous zap
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct EyeMetrics {
+    pub queue_depth: u64,
+    /// Average milliseconds per send
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub send_latency: Option<f64>,
+    pub dropped_zaps: u64,
+    /// Circuit state per telemetry endpoint
+    pub circuits: HashMap<String, CircuitState>,
+}
+
+/// State of the circuit breaker guarding a telemetry server
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Application metric reported by the command, counters and histograms cover the time since the previous zap
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum CustomMetric {
+    Gauge { value: f64 },
+    Counter { value: f64 },
+    Histogram { count: u64, min: f64, max: f64, mean: f64, p50: f64, p95: f64 },
+}
+
+/// Size of one tracked data path, `size` is absent if the path could not be read
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct DataUsage {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Rates are per second since the previous sample
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct ExtendedMetrics {
+    pub virtual_memory: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub read_rate: f64,
+    pub write_rate: f64,
+    pub status: String,
+    pub start_time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threads: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_fds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voluntary_context_switches: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub involuntary_context_switches: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_switch_rate: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct HostMetrics {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_memory: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub used_memory: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_swap: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub used_swap: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_average: Option<LoadAverage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disks: Option<Vec<DiskSpace>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub networks: Option<Vec<NetworkThroughput>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct DiskSpace {
+    pub mount_point: String,
+    pub total: u64,
+    pub available: u64,
+}
+
+/// Bytes per second received and transmitted since the previous sample
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct NetworkThroughput {
+    pub interface: String,
+    pub received: f64,
+    pub transmitted: f64,
 }
 
 impl Endpoint for Zap {
     fn endpoint(&self) -> &str {
         "zap"
     }
+
+    fn set_uuid(&mut self, uuid: &str) {
+        self.uuid = uuid.to_string();
+    }
 }
 
 impl Zap {
-    pub fn from_process(uuid: String, process: Option<&Process>, args: &Args, messages: Option<Vec<MessageBuffer>>) -> Self {
-        let mut memory = 0.0;
-        let mut cpu = 0.0;
-        let mut data_folder_size = None;
-
-        if !args.no_metrics {
-            if let Some(process) = process {
-                memory = process.memory() as f64;
-                cpu = process.cpu_usage() as f64;
-            }
-        }
-
-        // track data folder size
-        if let Some(data_folder) = &args.data_folder {
-            data_folder_size = Some(get_folder_size(data_folder));
-            debug!("Data folder size: {} KB", data_folder_size.unwrap());
-        }
-
-        let zap = Zap {
+    pub fn from_sample(uuid: String, run: RunIdentity, sample: Sample, messages: Option<Vec<MessageBuffer>>, custom: Option<HashMap<String, CustomMetric>>, acks: Option<Vec<CommandAck>>, eye: Option<EyeMetrics>) -> Self {
+        let (sequence, idempotency_key) = run.next_sequence();
+        Zap {
+            protocol_version: PROTOCOL_VERSION,
             uuid,
-            memory,
-            cpu,
+            run,
+            sequence,
+            idempotency_key,
+            memory: sample.memory,
+            cpu: sample.cpu,
             time: Utc::now().timestamp_millis() as u64,
-            disk: data_folder_size,
+            disk: sample.disk,
+            data: sample.data,
             messages,
-        };
-        zap
+            extended: sample.extended,
+            host: sample.host,
+            custom,
+            acks,
+            eye,
+            labels: None,
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Host, container and executable details that let the brain group processes
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct Identity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// Addresses of the host, without loopback and link-local addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_addresses: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_id: Option<String>,
+    /// Detected from the cgroups, e.g. `docker` or `containerd`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_runtime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kubernetes_pod: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kubernetes_namespace: Option<String>,
+    /// Resolved path of the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub executable: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub executable_sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eye_version: Option<String>,
+    /// Optional eye behaviour enabled for the run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Introduction {
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(flatten)]
+    pub run: RunIdentity,
+    #[serde(default)]
+    pub sequence: u64,
+    #[serde(default)]
+    pub idempotency_key: String,
+    #[serde(flatten)]
+    pub identity: Identity,
     pub pid: i32,
     pub parent_pid: i32,
     pub name: String,
@@ -167,6 +779,9 @@ pub struct Introduction {
     pub host: String,
     pub user: String,
     pub time: u64,
+    /// Encodings the eye can send, the brain answers with those it accepts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encodings: Option<Vec<Encoding>>,
 }
 
 impl Endpoint for Introduction {
@@ -176,61 +791,113 @@ impl Endpoint for Introduction {
 }
 
 impl Introduction {
-    pub fn from_child(parent_pid: i32, child_pid: i32, root_proc: &str, root_proc_args: &str, display_name: Option<String>) -> Self {
-        let introduction = Introduction {
+    pub fn from_child(run: RunIdentity, identity: Identity, parent_pid: i32, child_pid: i32, root_proc: &str, root_proc_args: &str, display_name: Option<String>) -> Self {
+        let (sequence, idempotency_key) = run.next_sequence();
+        Introduction {
+            protocol_version: PROTOCOL_VERSION,
+            run,
+            sequence,
+            idempotency_key,
+            identity,
             pid: child_pid,
-            parent_pid: parent_pid,
+            parent_pid,
             name: root_proc.to_string(),
             args: root_proc_args.to_string(),
             display_name,
             host: get_hostname(),
             user: get_current_user(),
             time: Utc::now().timestamp_millis() as u64,
-        };
-        introduction
+            encodings: Some(Encoding::ALL.to_vec()),
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Exit {
+    #[serde(default)]
+    pub protocol_version: u32,
     pub uuid: String,
+    #[serde(flatten)]
+    pub run: RunIdentity,
+    #[serde(default)]
+    pub sequence: u64,
+    #[serde(default)]
+    pub idempotency_key: String,
     pub exit_code: i32,
     pub time: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub messages: Option<Vec<MessageBuffer>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acks: Option<Vec<CommandAck>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<RunSummary>,
+}
+
+/// Statistics over a whole run, sent with its exit
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RunSummary {
+    /// Wall-clock seconds
+    pub runtime: f64,
+    /// CPU seconds of the command and the children it waited for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_cpu_time: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_cpu_time: Option<f64>,
+    /// Peak resident bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_memory: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average_cpu: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p95_cpu: Option<f64>,
+    /// Peak total size of the data folders
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_disk: Option<u64>,
+    pub stdout_lines: u64,
+    pub stderr_lines: u64,
+    /// Lines pushed out of the log buffer before they were sent
+    pub dropped_lines: u64,
+    pub telemetry_failures: u64,
 }
 
 impl Endpoint for Exit {
     fn endpoint(&self) -> &str {
         "exit"
     }
+
+    fn set_uuid(&mut self, uuid: &str) {
+        self.uuid = uuid.to_string();
+    }
 }
 
 impl Exit {
-    pub fn from_status(uuid: String, status: i32, messages: Option<Vec<MessageBuffer>>) -> Self {
-        Exit { uuid, exit_code: status, time: Utc::now().timestamp_millis() as u64, messages }
+    pub fn from_status(uuid: String, run: RunIdentity, status: i32, messages: Option<Vec<MessageBuffer>>, acks: Option<Vec<CommandAck>>, summary: Option<RunSummary>) -> Self {
+        let (sequence, idempotency_key) = run.next_sequence();
+        Exit { protocol_version: PROTOCOL_VERSION, uuid, run, sequence, idempotency_key, exit_code: status, time: Utc::now().timestamp_millis() as u64, messages, acks, summary }
     }
 }
 
+/// A payload sent to the brain with [`TelemetryClient::send`](crate::telemetry::TelemetryClient::send)
 pub trait Endpoint: MessagePack {
-    async fn send_telemetry(&self, endpoint: Option<String>) -> Result<String, BrainWaveError> {
-        match send_telemetry(self.endpoint(), self.to_vec().unwrap(), endpoint).await {
-            Ok(response) => Ok(response),
-            Err(BrainWaveError::ReqwestError(e)) => {
-                error!("Telemetry request failed: {}", e);
-                Ok("".to_string()) // Continue execution even if telemetry fails
-            },
-            Err(e) => Err(e), // Propagate other errors
-        }
-    }
-
     fn endpoint(&self) -> &str;
+
+    /// Stamp the payload with the uuid the receiving brain assigned to the run
+    fn set_uuid(&mut self, _uuid: &str) {}
+
+    /// Whether the brain can safely receive the payload twice
+    fn idempotent(&self) -> bool {
+        true
+    }
 }   
 
 pub trait MessagePack: Serialize {
     fn to_vec(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
         rmp_serde::to_vec_named(self)
     }
+
+    fn encode(&self, encoding: Encoding) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        encoding.encode(self)
+    }
 }
 
 // Implement for all types that satisfy the trait bounds
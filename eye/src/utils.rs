@@ -1,22 +1,24 @@
-use whoami;
-use hostname;
 use chrono::{Utc, DateTime, SecondsFormat};
 use std::io::{BufReader, BufRead};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
 use std::thread;
 use std::process::{ChildStdout, ChildStderr};
-use fs_extra::dir::get_size;
 use log::{info, debug};
 use std::fs::File;
 use std::io::Write;
 use crate::types::{MessageBuffer, Args, Zap};
+use crate::custom::CustomMetrics;
+use crate::summary::StreamCounts;
+use crate::systemd::{Journal, StartCondition};
 #[cfg(unix)]
 use tokio::signal::unix::{signal, SignalKind};
 #[cfg(windows)] 
 use tokio::signal::windows::ctrl_c;
-use std::sync::atomic::{AtomicBool, Ordering};
-use sysinfo::{System, Pid};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use sysinfo::{System, Pid, ProcessRefreshKind, ProcessesToUpdate, Signal};
 use std::time::Duration;
+use tokio::task::JoinHandle;
 
 pub fn get_current_user() -> String {
     whoami::username().to_string()
@@ -28,66 +30,145 @@ pub fn get_hostname() -> String {
         .unwrap_or_else(|_| "unknown".to_string())
 }
 
+/// Where output lines go besides the message buffers
+#[derive(Clone)]
+pub struct OutputSinks {
+    pub counts: Arc<StreamCounts>,
+    pub start: Arc<StartCondition>,
+    pub journal: Option<Arc<Journal>>,
+}
+
+impl OutputSinks {
+    /// Check the line against the start condition and write it to the journal, or the log
+    fn output(&self, line: &str, error: bool) {
+        self.start.observe(line);
+        if !self.journal.as_ref().is_some_and(|journal| journal.write(line, error)) {
+            info!("{}", line);
+        }
+    }
+
+    /// Like `output` for a line of a tailed file, logged after its source
+    pub fn output_from(&self, source: &str, line: &str) {
+        self.start.observe(line);
+        if !self.journal.as_ref().is_some_and(|journal| journal.write_from(source, line)) {
+            info!("{}: {}", source, line);
+        }
+    }
+}
+
+/// Add a message, keeping the newest `limit` and counting the ones pushed out
+pub fn push_message(buffer: &Mutex<Vec<MessageBuffer>>, message: MessageBuffer, limit: usize, dropped: Option<&AtomicU64>) {
+    if let Ok(mut message_buffer) = buffer.lock() {
+        message_buffer.push(message);
+
+        if message_buffer.len() > limit {
+            if let Some(dropped) = dropped {
+                dropped.fetch_add((message_buffer.len() - limit) as u64, Ordering::Relaxed);
+            }
+            let new_content = message_buffer[message_buffer.len() - limit..].to_vec();
+            *message_buffer = new_content;
+        }
+    }
+}
+
+/// Call `f` with every line until the end of the stream, invalid UTF-8 is replaced instead of ending the read
+/// so the child never gets a broken pipe from an eye that stopped reading
+fn for_each_line(mut reader: impl BufRead, mut f: impl FnMut(String)) {
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        match reader.read_until(b'\n', &mut buffer) {
+            Ok(0) => return,
+            Ok(_) => {
+                if buffer.ends_with(b"\n") {
+                    buffer.pop();
+                    if buffer.ends_with(b"\r") {
+                        buffer.pop();
+                    }
+                }
+                f(String::from_utf8_lossy(&buffer).into_owned());
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                debug!("Stopped reading output: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Lines read but not yet collected, a reader waits while the queue is full
+const LINE_QUEUE: usize = 1024;
+
+/// The threads reading the output of the command and the one collecting the lines they read
+pub struct StreamReaders {
+    stdout: thread::JoinHandle<()>,
+    stderr: thread::JoinHandle<()>,
+    collector: thread::JoinHandle<()>,
+}
+
+impl StreamReaders {
+    /// Wait until both streams are closed and every line is in the message buffers
+    pub fn join(self) {
+        self.stdout.join().unwrap();
+        self.stderr.join().unwrap();
+        // the readers held the only senders, the collector ends once the queue is empty
+        self.collector.join().unwrap();
+    }
+}
+
+/// Read the output of the command on a thread per stream. The readers only count and split
+/// lines, logging and buffering happen on a collector thread behind a bounded queue.
 pub fn read_streams(
     stdout: ChildStdout,
     stderr: ChildStderr,
     all_message_buffer: Arc<Mutex<Vec<MessageBuffer>>>,
     stderr_message_buffer: Arc<Mutex<Vec<MessageBuffer>>>,
     args: &Args,
-) -> (thread::JoinHandle<()>, thread::JoinHandle<()>) {
+    custom: Option<CustomMetrics>,
+    sinks: OutputSinks,
+) -> StreamReaders {
     let log_buffer_size = args.log_buffer_size;
     let error_log_buffer_size = args.error_log_buffer_size;
+    let (lines, received) = mpsc::sync_channel::<MessageBuffer>(LINE_QUEUE);
 
-    let message_all_clone = all_message_buffer.clone();
-    let stdout_handle = thread::spawn(move || {
-        let reader = BufReader::new(stdout);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                info!("{}", line);
-
-                if let Ok(mut message_buffer) = message_all_clone.lock() {
-                    message_buffer.push(MessageBuffer { message: line, timestamp: Utc::now().timestamp_millis() as u64, error: false });
-
-                    if message_buffer.len() > log_buffer_size {
-                        let new_content = message_buffer[message_buffer.len() - log_buffer_size..].to_vec();
-                        *message_buffer = new_content;
-                    }
-                }
+    let stdout_lines = lines.clone();
+    let stdout_counts = sinks.counts.clone();
+    let stdout = thread::spawn(move || {
+        for_each_line(BufReader::new(stdout), |line| {
+            stdout_counts.stdout.fetch_add(1, Ordering::Relaxed);
+            if custom.as_ref().is_some_and(|custom| custom.record_prefixed(&line)) {
+                return;
             }
-        }
+
+            let message = MessageBuffer { message: line, timestamp: Utc::now().timestamp_millis() as u64, error: false, source: None };
+            // the collector only goes away with the process
+            let _ = stdout_lines.send(message);
+        });
     });
 
-    let stderr_handle = thread::spawn(move || {
-        let reader = BufReader::new(stderr);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                info!("{}", line);
+    let stderr_counts = sinks.counts.clone();
+    let stderr = thread::spawn(move || {
+        for_each_line(BufReader::new(stderr), |line| {
+            stderr_counts.stderr.fetch_add(1, Ordering::Relaxed);
 
-                if let Ok(mut message_buffer) = stderr_message_buffer.lock() {
-                    message_buffer.push(MessageBuffer { message: line.clone(), timestamp: Utc::now().timestamp_millis() as u64, error: true });
+            let message = MessageBuffer { message: line, timestamp: Utc::now().timestamp_millis() as u64, error: true, source: None };
+            let _ = lines.send(message);
+        });
+    });
 
-                    if message_buffer.len() > error_log_buffer_size {
-                        let new_content = message_buffer[message_buffer.len() - error_log_buffer_size..].to_vec();
-                        *message_buffer = new_content;
-                    }
-                }
-                if let Ok(mut message_buffer) = all_message_buffer.lock() {
-                    message_buffer.push(MessageBuffer { message: line.clone(), timestamp: Utc::now().timestamp_millis() as u64, error: true });
+    let collector = thread::spawn(move || {
+        for message in received {
+            sinks.output(&message.message, message.error);
 
-                    if message_buffer.len() > log_buffer_size {
-                        let new_content = message_buffer[message_buffer.len() - log_buffer_size..].to_vec();
-                        *message_buffer = new_content;
-                    }
-                }
+            if message.error {
+                push_message(&stderr_message_buffer, message.clone(), error_log_buffer_size, None);
             }
+            push_message(&all_message_buffer, message, log_buffer_size, Some(&sinks.counts.dropped));
         }
     });
 
-    return (stdout_handle, stderr_handle);
-}
-
-pub fn get_folder_size(folder: &str) -> u64 {
-    get_size(folder).unwrap_or(0)
+    StreamReaders { stdout, stderr, collector }
 }
 
 pub fn log_zap(zap: &Zap, log_file: &mut Option<File>) {
@@ -95,7 +176,7 @@ pub fn log_zap(zap: &Zap, log_file: &mut Option<File>) {
         let mut messages = zap.messages.as_ref().map(|m| m.iter().map(|m| m.message.clone()).collect::<Vec<String>>()).unwrap_or(vec![]);
         let time: String = DateTime::from_timestamp_millis(zap.time as i64).unwrap().to_rfc3339_opts(SecondsFormat::Millis, true);
 
-        if messages.len() == 0 {
+        if messages.is_empty() {
             messages.push("".to_string());
         }
 
@@ -107,8 +188,19 @@ pub fn log_zap(zap: &Zap, log_file: &mut Option<File>) {
     }
 }
 
+/// Tasks stopping the child on SIGTERM and SIGINT, aborted when dropped so the signals only stop the run they belong to
+pub struct SignalHandlers(Vec<JoinHandle<()>>);
+
+impl Drop for SignalHandlers {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
 #[cfg(unix)]
-pub async fn setup_signal_handlers(child_pid: u32) {
+pub fn setup_signal_handlers(child_pid: u32) -> SignalHandlers {
     let shutting_down = Arc::new(AtomicBool::new(false));
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
     let mut sigint = signal(SignalKind::interrupt()).unwrap();
@@ -117,7 +209,7 @@ pub async fn setup_signal_handlers(child_pid: u32) {
     let shutting_down_int = shutting_down.clone();
 
     // Handle SIGTERM
-    tokio::spawn(async move {
+    let term = tokio::spawn(async move {
         sigterm.recv().await;
         if !shutting_down_term.swap(true, Ordering::SeqCst) {
             debug!("Received SIGTERM, initiating graceful shutdown...");
@@ -126,47 +218,59 @@ pub async fn setup_signal_handlers(child_pid: u32) {
     });
 
     // Handle SIGINT (Ctrl+C)
-    tokio::spawn(async move {
+    let int = tokio::spawn(async move {
         sigint.recv().await;
         if !shutting_down_int.swap(true, Ordering::SeqCst) {
             debug!("Received SIGINT, initiating graceful shutdown...");
             graceful_shutdown(child_pid).await;
         }
     });
+
+    SignalHandlers(vec![term, int])
 }
 
 #[cfg(windows)]
-pub async fn setup_signal_handlers(child_pid: u32) {
+pub fn setup_signal_handlers(child_pid: u32) -> SignalHandlers {
     let shutting_down = Arc::new(AtomicBool::new(false));
     let mut sigterm = ctrl_c().unwrap();
 
     let shutting_down_term = shutting_down.clone();
 
     // Handle SIGTERM
-    tokio::spawn(async move {
+    let term = tokio::spawn(async move {
         sigterm.recv().await;
         if !shutting_down_term.swap(true, Ordering::SeqCst) {
             debug!("Received SIGTERM, initiating graceful shutdown...");
             graceful_shutdown(child_pid).await;
         }
     });
+
+    SignalHandlers(vec![term])
+}
+
+/// Build a system handle that only knows about the given process, much cheaper than `System::new_all`
+pub fn process_system(pid: u32) -> System {
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[Pid::from_u32(pid)]), true, ProcessRefreshKind::nothing());
+    sys
 }
 
 async fn graceful_shutdown(child_pid: u32) {
-    let sys = System::new_all();
+    let sys = process_system(child_pid);
     
     // Try to terminate child process gracefully first
-    if let Some(process) = sys.process(Pid::from(child_pid as usize)) {
+    if let Some(process) = sys.process(Pid::from_u32(child_pid)) {
         debug!("Sending SIGTERM to child process {}", child_pid);
-        process.kill_with(sysinfo::Signal::Term);
+        process.kill_with(Signal::Term);
         
         // Give the process some time to cleanup
         tokio::time::sleep(Duration::from_secs(5)).await;
         
         // If still running, force kill
-        if let Some(process) = sys.process(Pid::from(child_pid as usize)) {
+        let sys = process_system(child_pid);
+        if let Some(process) = sys.process(Pid::from_u32(child_pid)) {
             debug!("Child process still running, sending SIGKILL");
-            process.kill_with(sysinfo::Signal::Kill);
+            process.kill_with(Signal::Kill);
         }
     }
 }
@@ -0,0 +1,182 @@
+//! Helpers shared by the integration tests: the eye binary, a mock brain and child scripts.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use serde_json::Value;
+
+/// The bb_eye binary built for this test run
+pub fn eye() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_bb_eye"))
+}
+
+/// Wait until the condition holds, false if it did not within the timeout
+pub fn wait_for(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    condition()
+}
+
+/// Wait for the child to exit, killing it after the timeout
+pub fn wait_with_timeout(child: &mut Child, timeout: Duration) -> Option<std::process::ExitStatus> {
+    let mut status = None;
+    wait_for(timeout, || {
+        status = child.try_wait().unwrap();
+        status.is_some()
+    });
+    if status.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    status
+}
+
+/// Collect the output of the child until it exits, panics after the timeout
+pub fn output_with_timeout(child: Child, timeout: Duration) -> std::process::Output {
+    let pid = child.id();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    // reading on a thread keeps a chatty child from blocking on a full pipe
+    std::thread::spawn(move || sender.send(child.wait_with_output()));
+
+    match receiver.recv_timeout(timeout) {
+        Ok(output) => output.unwrap(),
+        Err(_) => {
+            let _ = Command::new("kill").args(["-KILL", &pid.to_string()]).status();
+            panic!("bb_eye did not exit within {:?}", timeout);
+        }
+    }
+}
+
+/// A `bb_eye brain-mock` on a free port, stopped when dropped
+pub struct Mock {
+    child: Child,
+    address: String,
+    pub url: String,
+}
+
+impl Mock {
+    /// Start a mock answering with the given JSON rules
+    pub fn start(rules: &str) -> Mock {
+        Mock::with_args(&[], rules)
+    }
+
+    /// Start a mock with extra `brain-mock` arguments
+    pub fn with_args(args: &[&str], rules: &str) -> Mock {
+        let mut child = eye()
+            .args(["brain-mock", "--port", "0"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the mock brain");
+
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+        let url = line.trim().strip_prefix("Listening on ").expect("mock did not print its address").to_string();
+        let address = url.trim_start_matches("http://").trim_end_matches("/telemetry").to_string();
+
+        let mock = Mock { child, address, url };
+        if !rules.is_empty() {
+            mock.request("POST", "/mock/script", rules);
+        }
+        mock
+    }
+
+    /// Every payload the mock accepted so far
+    pub fn received(&self) -> Value {
+        serde_json::from_str(&self.request("GET", "/mock/received", "")).unwrap()
+    }
+
+    pub fn count(&self, kind: &str) -> usize {
+        self.received()[kind].as_array().map_or(0, Vec::len)
+    }
+
+    fn request(&self, method: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", method, path, self.address, body.len(), body).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"), "mock answered {}", head);
+        body.to_string()
+    }
+}
+
+impl Drop for Mock {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A directory for child scripts and their files, removed when dropped
+pub struct Scratch {
+    pub dir: PathBuf,
+}
+
+impl Scratch {
+    pub fn new(name: &str) -> Scratch {
+        let dir = std::env::temp_dir().join(format!("bb_eye_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Scratch { dir }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Write an executable shell script, returns its path
+    pub fn script(&self, name: &str, body: &str) -> String {
+        let path = self.path(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        std::fs::read_to_string(self.path(name)).unwrap_or_default()
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        Path::new(&self.path(name)).exists()
+    }
+
+    /// Files in a directory of the scratch with the given extension, sorted
+    pub fn files(&self, dir: &str, extension: &str) -> Vec<PathBuf> {
+        files(&self.path(dir), extension)
+    }
+}
+
+/// Files in the directory with the given extension, sorted, none if it does not exist
+pub fn files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir).map(|files| {
+        files.filter_map(Result::ok).map(|file| file.path()).filter(|path| path.extension().is_some_and(|found| found == extension)).collect()
+    }).unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// Send one line to a control socket and return the reply line
+pub fn control(socket: &Path, request: &str) -> String {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket).unwrap();
+    stream.write_all(format!("{}\n", request).as_bytes()).unwrap();
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).unwrap();
+    reply
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
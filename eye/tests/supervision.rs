@@ -0,0 +1,628 @@
+//! End-to-end tests running the real eye against the mock brain and small child scripts.
+#![cfg(unix)]
+
+mod common;
+
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+use bb_eye::types::Args;
+use bb_eye::Supervisor;
+use clap::Parser;
+use serde_json::Value;
+use common::{control, eye, files, output_with_timeout, wait_for, wait_with_timeout, Mock, Scratch};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Run the eye against the mock until it exits
+fn run(mock: &Mock, extra: &[&str], command: &[&str]) -> Output {
+    let child = eye()
+        .args(["-e", &mock.url, "-i", "0.2"])
+        .args(extra)
+        .args(command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    output_with_timeout(child, TIMEOUT)
+}
+
+fn messages(zaps: &Value) -> Vec<(String, bool)> {
+    zaps.as_array().unwrap().iter()
+        .flat_map(|zap| zap["messages"].as_array().cloned().unwrap_or_default())
+        .map(|message| (message["message"].as_str().unwrap().to_string(), message["error"].as_bool().unwrap()))
+        .collect()
+}
+
+#[test]
+fn passes_arguments_to_the_command() {
+    let scratch = Scratch::new("arguments");
+    let script = scratch.script("args.sh", r#"echo "args: $# $1 $2""#);
+    let mock = Mock::start("");
+
+    let output = run(&mock, &[], &[&script, "first", "--second"]);
+
+    assert!(String::from_utf8_lossy(&output.stderr).contains("args: 2 first --second"));
+    let introduction = &mock.received()["introductions"][0];
+    assert_eq!(introduction["name"], script.as_str());
+    assert_eq!(introduction["args"], "first --second");
+}
+
+#[test]
+fn reports_the_exit_code() {
+    let scratch = Scratch::new("exit_code");
+    let script = scratch.script("fail.sh", "exit 3");
+    let mock = Mock::start("");
+
+    run(&mock, &[], &[&script]);
+
+    let exits = &mock.received()["exits"];
+    assert_eq!(exits.as_array().unwrap().len(), 1);
+    assert_eq!(exits[0]["exit_code"], 3);
+}
+
+#[test]
+fn restarts_up_to_the_maximum_with_backoff() {
+    let scratch = Scratch::new("restarts");
+    let script = scratch.script("fail.sh", "exit 1");
+    let mock = Mock::start("");
+
+    let started = Instant::now();
+    run(&mock, &["--restart", "--max-restarts", "3"], &[&script]);
+
+    // one and then two seconds between the three runs
+    assert!(started.elapsed() >= Duration::from_secs(3), "restarted after {:?}", started.elapsed());
+    let received = mock.received();
+    let introductions = received["introductions"].as_array().unwrap();
+    assert_eq!(introductions.len(), 3);
+    for (index, introduction) in introductions.iter().enumerate() {
+        assert_eq!(introduction["restart_index"], index);
+        assert_eq!(introduction["session_id"], introductions[0]["session_id"]);
+    }
+    assert_eq!(received["exits"].as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn does_not_restart_a_successful_command() {
+    let scratch = Scratch::new("success");
+    let script = scratch.script("ok.sh", "exit 0");
+    let mock = Mock::start("");
+
+    run(&mock, &["--restart"], &[&script]);
+
+    assert_eq!(mock.count("introductions"), 1);
+}
+
+#[test]
+fn restarts_when_the_brain_asks() {
+    let scratch = Scratch::new("brain_restart");
+    // only the second run finishes on its own, exec so the eye stops the sleep itself
+    let script = scratch.script("once.sh", &format!(
+        "if [ -e {0} ]; then exit 0; fi\ntouch {0}\nexec sleep 20", scratch.path("started").display()
+    ));
+    let mock = Mock::start(r#"[{"endpoint": "zap", "skip": 2, "times": 1, "command": "restart"}]"#);
+
+    let started = Instant::now();
+    run(&mock, &[], &[&script]);
+
+    assert!(started.elapsed() < Duration::from_secs(15));
+    let received = mock.received();
+    assert_eq!(received["introductions"].as_array().unwrap().len(), 2);
+    assert_eq!(received["introductions"][1]["restart_index"], 1);
+    assert_eq!(received["exits"].as_array().unwrap().last().unwrap()["exit_code"], 0);
+}
+
+#[test]
+fn exits_when_the_brain_asks() {
+    let scratch = Scratch::new("brain_exit");
+    let script = scratch.script("forever.sh", "exec sleep 20");
+    let mock = Mock::start(r#"[{"endpoint": "zap", "skip": 2, "times": 1, "command": "exit"}]"#);
+
+    let started = Instant::now();
+    run(&mock, &["--restart"], &[&script]);
+
+    assert!(started.elapsed() < Duration::from_secs(15));
+    assert_eq!(mock.count("introductions"), 1);
+}
+
+#[test]
+fn applies_a_command_answered_to_the_introduction() {
+    let scratch = Scratch::new("introduction_command");
+    let script = scratch.script("forever.sh", &format!("echo $$ > {}\nexec sleep 20", scratch.path("pid").display()));
+    let mock = Mock::start(r#"[{"endpoint": "introduction", "times": 1, "command": "exit"}]"#);
+
+    let started = Instant::now();
+    run(&mock, &["--restart"], &[&script]);
+
+    assert!(started.elapsed() < Duration::from_secs(15));
+    assert_eq!(mock.count("exits"), 1);
+    // the command was stopped, not left running behind the eye
+    let pid = scratch.read("pid").trim().to_string();
+    assert!(!Command::new("kill").args(["-0", &pid]).stderr(Stdio::null()).status().unwrap().success());
+}
+
+/// Send a signal to the eye and check the command was stopped with SIGTERM
+fn forwards_signal(signal: &str) {
+    let scratch = Scratch::new(&format!("signal_{}", signal));
+    let script = scratch.script("trap.sh", &format!(
+        "trap 'echo terminated > {0}; exit 143' TERM\ntouch {1}\nwhile true; do sleep 0.1; done",
+        scratch.path("terminated").display(),
+        scratch.path("ready").display(),
+    ));
+
+    let mut child = eye().args(["-x", &script]).stderr(Stdio::null()).spawn().unwrap();
+    assert!(wait_for(TIMEOUT, || scratch.exists("ready")), "command did not start");
+
+    Command::new("kill").args([&format!("-{}", signal), &child.id().to_string()]).status().unwrap();
+
+    assert!(wait_with_timeout(&mut child, TIMEOUT).is_some(), "eye did not exit after SIG{}", signal);
+    assert_eq!(scratch.read("terminated").trim(), "terminated");
+}
+
+#[test]
+fn forwards_sigterm_to_the_command() {
+    forwards_signal("TERM");
+}
+
+#[test]
+fn stops_the_command_on_sigint() {
+    forwards_signal("INT");
+}
+
+#[test]
+fn captures_logs_in_order_and_truncates_the_buffer() {
+    let scratch = Scratch::new("logs");
+    let script = scratch.script("chatty.sh", "i=1\nwhile [ $i -le 200 ]; do echo $i; i=$((i + 1)); done\nsleep 0.5\necho oops >&2\nsleep 1");
+    let mock = Mock::start("");
+
+    run(&mock, &["--log-buffer-size", "20"], &[&script]);
+
+    let received = mock.received();
+    for zap in received["zaps"].as_array().unwrap() {
+        assert!(zap["messages"].as_array().map_or(0, Vec::len) <= 20);
+    }
+
+    let messages = messages(&received["zaps"]);
+    let numbers: Vec<u32> = messages.iter().filter(|(_, error)| !error).map(|(message, _)| message.parse().unwrap()).collect();
+    assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]), "lines out of order: {:?}", numbers);
+    assert_eq!(numbers.last(), Some(&200));
+    assert!(messages.contains(&("oops".to_string(), true)));
+
+    let summary = &received["exits"][0]["summary"];
+    assert_eq!(summary["stdout_lines"], 200);
+    assert_eq!(summary["stderr_lines"], 1);
+    assert!(summary["dropped_lines"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn summarises_output_and_cpu_of_a_command_that_exits_right_away() {
+    let scratch = Scratch::new("summary");
+    let script = scratch.script("burst.sh", "i=0\nwhile [ $i -lt 100000 ]; do i=$((i + 1)); done\nseq 1 100000\necho last words >&2\nexit 4");
+    let mock = Mock::start("");
+
+    run(&mock, &[], &[&script]);
+
+    let exit = &mock.received()["exits"][0];
+    assert_eq!(exit["summary"]["stdout_lines"], 100000);
+    assert_eq!(exit["summary"]["stderr_lines"], 1);
+    assert!(exit["summary"]["user_cpu_time"].as_f64().unwrap() > 0.0, "summary: {}", exit["summary"]);
+    assert_eq!(exit["messages"][0]["message"], "last words");
+}
+
+#[test]
+fn keeps_reading_after_invalid_utf8() {
+    let scratch = Scratch::new("invalid_utf8");
+    let script = scratch.script("binary.sh", "printf 'before\\n\\377\\376\\n'\nsleep 0.5\necho after\nsleep 1");
+    let mock = Mock::start("");
+
+    let output = run(&mock, &[], &[&script]);
+
+    assert!(output.status.success());
+    let messages = messages(&mock.received()["zaps"]);
+    assert!(messages.contains(&("\u{FFFD}\u{FFFD}".to_string(), false)), "messages: {:?}", messages);
+    assert!(messages.contains(&("after".to_string(), false)), "messages: {:?}", messages);
+}
+
+/// Through the library, the runtime outlives the run there and cannot clean up for it
+#[tokio::test]
+async fn releases_the_metrics_listeners_when_the_run_ends() {
+    let scratch = Scratch::new("metrics_socket");
+    let runtime_dir = scratch.path("runtime").to_string_lossy().to_string();
+    let script = scratch.script("socket.sh", &format!("echo $BB_EYE_METRICS_SOCKET > {}", scratch.path("socket").display()));
+    // a free port, released again for the eye
+    let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+    let args = Args::try_parse_from(["bb_eye", "-x", "-n", "--runtime-dir", &runtime_dir, "--metrics-socket", "--statsd-port", &port, &script]).unwrap();
+
+    Supervisor::from_args(args).without_signal_handlers().run().await;
+
+    let socket = scratch.read("socket");
+    assert!(socket.trim().starts_with(&runtime_dir), "{} is outside the runtime directory", socket.trim());
+    assert!(!std::path::Path::new(socket.trim()).exists(), "{} was left behind", socket.trim());
+    assert!(std::net::UdpSocket::bind(format!("127.0.0.1:{}", port)).is_ok(), "the StatsD port is still taken");
+}
+
+#[tokio::test]
+async fn keeps_the_history_and_registration_of_each_supervisor_apart() {
+    let scratch = Scratch::new("two_supervisors");
+    let runtime_dir = scratch.path("runtime").to_string_lossy().to_string();
+    let supervisor = |name: &str| {
+        let script = scratch.script(&format!("{}.sh", name), &format!("echo {}\nsleep 2", name));
+        let history = scratch.path(&format!("{}.db", name)).to_string_lossy().to_string();
+        let args = Args::try_parse_from(["bb_eye", "-x", "-n", "--runtime-dir", &runtime_dir, "--history", &history, &script]).unwrap();
+        Supervisor::from_args(args).without_signal_handlers()
+    };
+    let (first, second) = (supervisor("first"), supervisor("second"));
+
+    // the control sockets are served by this runtime, so they are asked from a blocking thread
+    let dir = scratch.path("runtime");
+    let statuses = tokio::task::spawn_blocking(move || {
+        let sockets = || files(&dir, "sock");
+        assert!(wait_for(TIMEOUT, || sockets().len() == 2), "sockets: {:?}", sockets());
+        sockets().iter().map(|socket| serde_json::from_str::<Value>(&control(socket, "status")).unwrap()).collect::<Vec<_>>()
+    });
+
+    let (_, _, statuses) = tokio::join!(first.run(), second.run(), statuses);
+
+    let statuses = statuses.unwrap();
+    assert_ne!(statuses[0]["session_id"], statuses[1]["session_id"]);
+    let mut commands: Vec<&str> = statuses.iter().map(|status| status["command"].as_str().unwrap()).collect();
+    commands.sort();
+    assert!(commands[0].ends_with("first.sh") && commands[1].ends_with("second.sh"), "commands: {:?}", commands);
+    assert_eq!(scratch.files("runtime", "json").len() + scratch.files("runtime", "sock").len(), 0, "entries were left behind");
+
+    for name in ["first", "second"] {
+        let connection = bb_eye::history::connect(scratch.path(&format!("{}.db", name)).to_str().unwrap()).unwrap();
+        let names: Vec<String> = connection.prepare("SELECT name FROM runs").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(names.len(), 1, "runs in {}: {:?}", name, names);
+        assert!(names[0].ends_with(&format!("{}.sh", name)), "runs in {}: {:?}", name, names);
+    }
+}
+
+/// Both commands are children of this process, only the burning one may count its CPU time
+#[tokio::test]
+async fn summarises_the_cpu_of_each_command_alone() {
+    let scratch = Scratch::new("concurrent_summary");
+    let mock = Mock::start("");
+    let supervisor = |name: &str, body: &str| {
+        let script = scratch.script(&format!("{}.sh", name), body);
+        let args = Args::try_parse_from(["bb_eye", "-n", "-e", &mock.url, &script]).unwrap();
+        Supervisor::from_args(args).without_signal_handlers()
+    };
+    let burner = supervisor("burner", "i=0\nwhile [ $i -lt 300000 ]; do i=$((i + 1)); done");
+    let sleeper = supervisor("sleeper", "sleep 2");
+
+    tokio::join!(burner.run(), sleeper.run());
+
+    let received = mock.received();
+    let exits = received["exits"].as_array().unwrap();
+    assert_eq!(exits.len(), 2, "exits: {:?}", exits);
+    let cpu = |name: &str| {
+        let introduction = received["introductions"].as_array().unwrap().iter()
+            .find(|introduction| format!("{} {}", introduction["name"], introduction["args"]).contains(&format!("{}.sh", name)))
+            .unwrap();
+        let exit = exits.iter().find(|exit| exit["session_id"] == introduction["session_id"]).unwrap();
+        exit["summary"]["user_cpu_time"].as_f64().unwrap()
+    };
+    assert!(cpu("burner") > 0.1, "burner: {}", cpu("burner"));
+    assert!(cpu("sleeper") < 0.05, "sleeper took the CPU time of the burner: {}", cpu("sleeper"));
+}
+
+#[test]
+fn queries_the_stored_lines_with_their_source() {
+    let scratch = Scratch::new("query");
+    let log = scratch.path("app.log").to_string_lossy().to_string();
+    std::fs::write(&log, "").unwrap();
+    let script = scratch.script("service.sh", &format!("echo out\nsleep 0.5\necho tailed >> {}\nsleep 1.5", log));
+    let history = scratch.path("history.db").to_string_lossy().to_string();
+
+    let child = eye().args(["-x", "-n", "--history", &history, "--tail-file", &format!("{}:app", log), &script]).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap();
+    assert!(output_with_timeout(child, TIMEOUT).status.success());
+
+    let query = |args: &[&str]| {
+        let child = eye().args(args).args(["--history", &history]).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap();
+        String::from_utf8_lossy(&output_with_timeout(child, TIMEOUT).stdout).to_string()
+    };
+    let logs = query(&["logs"]);
+    assert!(logs.lines().any(|line| line.ends_with(" stdout out")), "logs: {}", logs);
+    assert!(logs.lines().any(|line| line.ends_with(" app tailed")), "logs: {}", logs);
+    // wildcards in the prefix are taken literally
+    assert_eq!(query(&["logs", "--run", "%"]), "");
+    assert_eq!(query(&["history", "--run", "_"]).lines().count(), 1);
+}
+
+#[test]
+fn writes_zaps_to_the_log_file() {
+    let scratch = Scratch::new("log_file");
+    let script = scratch.script("hello.sh", "echo hello\nsleep 0.5");
+    let log = scratch.path("eye.log");
+
+    let mut child = eye().args(["-x", "-i", "0.2", "-l", log.to_str().unwrap(), &script]).stderr(Stdio::null()).spawn().unwrap();
+    assert!(wait_with_timeout(&mut child, TIMEOUT).is_some());
+
+    let content = scratch.read("eye.log");
+    let line = content.lines().find(|line| line.ends_with(", hello")).expect("no line for the output");
+    let (time, _) = line.split_once(": ").unwrap();
+    assert!(chrono::DateTime::parse_from_rfc3339(time).is_ok(), "bad time in {}", line);
+}
+
+#[test]
+fn delays_telemetry() {
+    let scratch = Scratch::new("delay");
+    let script = scratch.script("slow.sh", "sleep 3");
+    let mock = Mock::start("");
+
+    let mut child = eye().args(["-e", &mock.url, "-t", "2", &script]).stderr(Stdio::null()).spawn().unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(mock.count("introductions"), 0);
+
+    assert!(wait_with_timeout(&mut child, TIMEOUT).is_some());
+    assert_eq!(mock.count("introductions"), 1);
+}
+
+#[test]
+fn records_payloads_when_they_are_sent() {
+    let scratch = Scratch::new("record");
+    let script = scratch.script("slow.sh", "sleep 3");
+    let recording = scratch.path("run.bbrec").to_string_lossy().to_string();
+    let mock = Mock::start("");
+
+    let started = chrono::Utc::now().timestamp_millis() as u64;
+    let mut child = eye().args(["-e", &mock.url, "-t", "2", "--record", &recording, &script]).stderr(Stdio::null()).spawn().unwrap();
+    assert!(wait_with_timeout(&mut child, TIMEOUT).is_some());
+
+    // the first frame after the magic is the introduction, held back by the delay
+    let bytes = std::fs::read(&recording).unwrap();
+    let time = u64::from_be_bytes(bytes[6..14].try_into().unwrap());
+    assert_eq!(&bytes[15..15 + bytes[14] as usize], b"introduction");
+    assert!(time >= started + 2000, "recorded {}ms after the start", time - started);
+
+    let replayed = Mock::start("");
+    let child = eye().args(["replay", "--fast", "-e", &replayed.url, &recording]).stderr(Stdio::null()).spawn().unwrap();
+    assert!(output_with_timeout(child, TIMEOUT).status.success());
+    assert_eq!(replayed.count("introductions"), 1);
+    assert_eq!(replayed.count("exits"), 1);
+}
+
+#[test]
+fn runs_the_command_without_a_reachable_brain() {
+    let scratch = Scratch::new("unreachable");
+    let script = scratch.script("echo.sh", "echo still running");
+    // nothing listens on the discard port
+    let child = eye()
+        .args(["-e", "http://127.0.0.1:9/telemetry", "--connect-timeout", "0.5", "--max-retries", "0", "-i", "0.2", &script])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let output = output_with_timeout(child, TIMEOUT);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("still running"));
+}
+
+#[test]
+fn sends_the_chosen_encoding() {
+    let scratch = Scratch::new("encoding");
+    let script = scratch.script("hello.sh", "echo hello\nsleep 0.5");
+    let mock = Mock::start("");
+
+    run(&mock, &["--encoding", "cbor"], &[&script]);
+
+    let received = mock.received();
+    assert_eq!(received["introductions"][0]["protocol_version"], 2);
+    assert!(received["encodings"].as_array().unwrap().iter().all(|encoding| encoding == "cbor"));
+    assert!(messages(&received["zaps"]).contains(&("hello".to_string(), false)));
+}
+
+#[test]
+fn falls_back_to_msgpack_when_the_brain_refuses_the_encoding() {
+    let scratch = Scratch::new("encoding_fallback");
+    let script = scratch.script("hello.sh", "echo hello\nsleep 0.5");
+    let mock = Mock::with_args(&["--accept", "msgpack"], "");
+
+    run(&mock, &["--encoding", "json"], &[&script]);
+
+    let received = mock.received();
+    assert_eq!(received["introductions"].as_array().unwrap().len(), 1);
+    assert!(received["encodings"].as_array().unwrap().iter().all(|encoding| encoding == "msgpack"));
+    assert_eq!(received["exits"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn numbers_payloads_and_drops_retried_duplicates() {
+    let scratch = Scratch::new("sequence");
+    let script = scratch.script("hello.sh", "echo hello\nsleep 1");
+    // the first zap is stored after the eye gave up on it, so its retry is a duplicate
+    let mock = Mock::start(r#"[{"endpoint": "zap", "times": 1, "delay": 1.5}]"#);
+
+    run(&mock, &["--request-timeout", "0.5"], &[&script]);
+
+    let received = mock.received();
+    assert_eq!(received["duplicates"].as_array().unwrap().len(), 1);
+    assert_eq!(received["introductions"][0]["sequence"], 0);
+
+    let mut sequences: Vec<u64> = received["zaps"].as_array().unwrap().iter().map(|zap| zap["sequence"].as_u64().unwrap()).collect();
+    sequences.sort();
+    assert!(sequences.windows(2).all(|pair| pair[0] < pair[1]), "duplicate sequences: {:?}", sequences);
+    let exit = &received["exits"][0];
+    assert_eq!(exit["sequence"].as_u64(), sequences.last().map(|last| last + 1));
+    assert_eq!(exit["idempotency_key"], format!("{}:{}", exit["run_id"].as_str().unwrap(), exit["sequence"]));
+}
+
+#[test]
+fn notifies_systemd_once_the_command_is_ready() {
+    let scratch = Scratch::new("notify");
+    let script = scratch.script("service.sh", "echo \"notify: ${NOTIFY_SOCKET:-unset}\"\nsleep 0.5\necho listening\nsleep 0.5");
+    let socket_path = scratch.path("notify.sock");
+    let socket = std::os::unix::net::UnixDatagram::bind(&socket_path).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+
+    let child = eye()
+        .args(["-x", "--ready-pattern", "^listening$", &script])
+        .env("NOTIFY_SOCKET", &socket_path)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let output = output_with_timeout(child, TIMEOUT);
+
+    let mut buffer = [0; 4096];
+    let mut states = Vec::new();
+    while let Ok(length) = socket.recv(&mut buffer) {
+        states.push(String::from_utf8_lossy(&buffer[..length]).to_string());
+    }
+
+    let ready = states.iter().position(|state| state == "READY=1").expect("no READY=1");
+    assert!(states[0].starts_with("STATUS=Starting"), "states: {:?}", states);
+    assert!(states[ready + 1].starts_with("STATUS=Running"), "states: {:?}", states);
+    assert!(states.last().unwrap().starts_with("STOPPING=1\nSTATUS=Stopped"), "states: {:?}", states);
+    // the command does not inherit the socket, only the eye notifies
+    assert!(String::from_utf8_lossy(&output.stderr).contains("notify: unset"));
+}
+
+#[test]
+fn pings_the_watchdog_while_waiting_for_a_brain() {
+    let scratch = Scratch::new("watchdog");
+    let socket_path = scratch.path("notify.sock");
+    let socket = std::os::unix::net::UnixDatagram::bind(&socket_path).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    // connections are accepted by the kernel but never answered
+    let brain = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/telemetry", brain.local_addr().unwrap());
+
+    let started = Instant::now();
+    let mut child = eye()
+        .args(["-e", &url, "--request-timeout", "2", "--max-retries", "0", "true"])
+        .env("NOTIFY_SOCKET", &socket_path)
+        .env("WATCHDOG_USEC", "1000000")
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut pings = vec![started];
+    let mut buffer = [0; 4096];
+    while child.try_wait().unwrap().is_none() {
+        assert!(started.elapsed() < TIMEOUT, "bb_eye did not exit within {:?}", TIMEOUT);
+        if let Ok(length) = socket.recv(&mut buffer) {
+            if &buffer[..length] == b"WATCHDOG=1" {
+                pings.push(Instant::now());
+            }
+        }
+    }
+    pings.push(Instant::now());
+
+    // the introduction and the exit each wait out the request timeout
+    assert!(started.elapsed() > Duration::from_secs(4));
+    let longest = pings.windows(2).map(|pair| pair[1] - pair[0]).max().unwrap();
+    assert!(longest < Duration::from_secs(1), "longest gap between pings: {:?}", longest);
+}
+
+#[test]
+fn detaches_and_lists_running_eyes() {
+    let scratch = Scratch::new("detach");
+    let runtime_dir = scratch.path("runtime").to_string_lossy().to_string();
+    let pidfile = scratch.path("eye.pid").to_string_lossy().to_string();
+    let script = scratch.script("service.sh", "exec sleep 30");
+    let list = || {
+        let output = eye().args(["--runtime-dir", &runtime_dir, "list"]).output().unwrap();
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    // global options may come before the subcommand
+    let output = eye().args(["--runtime-dir", &runtime_dir, "run", "--detach", "--pidfile", &pidfile, "-x", &script]).output().unwrap();
+    assert!(output.status.success());
+    let pid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    assert_eq!(scratch.read("eye.pid").trim(), pid);
+
+    assert!(wait_for(TIMEOUT, || scratch.files("runtime", "sock").len() == 1));
+    let socket = scratch.files("runtime", "sock").remove(0);
+    assert!(socket.file_name().unwrap().to_string_lossy().starts_with(&format!("{}-", pid)));
+    assert!(list().lines().any(|line| line.trim_start().starts_with(&pid) && line.ends_with(&script)));
+
+    // a second eye refuses the pidfile of the running one
+    let output = eye().args(["run", "--detach", "--pidfile", &pidfile, "-x", &script]).output().unwrap();
+    assert!(!output.status.success());
+
+    let status: Value = serde_json::from_str(&control(&socket, "status")).unwrap();
+    assert_eq!(status["pid"].to_string(), pid);
+    let circuits = status["circuits"].as_object().unwrap();
+    assert_eq!(circuits.len(), 1, "status: {}", status);
+    assert!(circuits.values().all(|state| state == "closed"), "status: {}", status);
+
+    assert_eq!(control(&socket, "exit"), "ok\n");
+
+    assert!(wait_for(TIMEOUT, || !scratch.exists("eye.pid")));
+    assert_eq!(list().lines().count(), 1, "only the header is left");
+}
+
+#[test]
+fn lets_one_of_several_eyes_claim_a_pidfile() {
+    let scratch = Scratch::new("pidfile_race");
+    let runtime_dir = scratch.path("runtime").to_string_lossy().to_string();
+    let pidfile = scratch.path("eye.pid").to_string_lossy().to_string();
+    let script = scratch.script("service.sh", "exec sleep 30");
+
+    let eyes: Vec<_> = (0..5)
+        .map(|_| eye().args(["--runtime-dir", &runtime_dir, "run", "--detach", "--pidfile", &pidfile, "-x", &script]).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap())
+        .collect();
+    let detached: Vec<String> = eyes.into_iter()
+        .map(|eye| output_with_timeout(eye, TIMEOUT))
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .collect();
+
+    assert_eq!(detached.len(), 1, "detached: {:?}", detached);
+    assert_eq!(scratch.read("eye.pid").trim(), detached[0]);
+
+    assert!(wait_for(TIMEOUT, || scratch.files("runtime", "sock").len() == 1));
+    // the socket is served before the run starts
+    let socket = scratch.files("runtime", "sock").remove(0);
+    assert!(wait_for(TIMEOUT, || control(&socket, "exit") == "ok\n"));
+    assert!(wait_for(TIMEOUT, || !scratch.exists("eye.pid")));
+}
+
+#[test]
+fn attaches_to_a_running_process_until_it_exits() {
+    let mock = Mock::start("");
+    let mut service = Command::new("sleep").arg("1").spawn().unwrap();
+    let pid = service.id().to_string();
+
+    let child = eye()
+        .args(["-e", &mock.url, "attach", "--pid", &pid, "-i", "0.2"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    output_with_timeout(child, TIMEOUT);
+    service.wait().unwrap();
+
+    let received = mock.received();
+    assert_eq!(received["introductions"][0]["pid"].to_string(), pid);
+    assert!(received["introductions"][0]["name"].as_str().unwrap().ends_with("sleep"));
+    assert_eq!(received["introductions"][0]["args"], "1");
+    assert!(!received["zaps"].as_array().unwrap().is_empty());
+    // not the eye's child, so the exit code and CPU time are unknown
+    assert_eq!(received["exits"][0]["exit_code"], -1);
+    assert!(received["exits"][0]["summary"]["user_cpu_time"].is_null());
+}
+
+#[test]
+fn tails_log_files_through_rotation() {
+    let scratch = Scratch::new("tail");
+    let log = scratch.path("app.log").to_string_lossy().to_string();
+    std::fs::write(&log, "written before the eye started\n").unwrap();
+    let script = scratch.script("service.sh", &format!("sleep 0.5\necho first >> {log}\nsleep 0.5\nmv {log} {log}.1\necho last words >> {log}.1\necho second >> {log}\nsleep 2.5", log = log));
+    let mock = Mock::start("");
+
+    let state = scratch.path("tail.json").to_string_lossy().to_string();
+    run(&mock, &["--tail-file", &format!("{}:app", log), "--tail-state", &state], &[&script]);
+
+    let tailed: Vec<(String, String)> = mock.received()["zaps"].as_array().unwrap().iter()
+        .flat_map(|zap| zap["messages"].as_array().cloned().unwrap_or_default())
+        .filter_map(|message| Some((message["source"].as_str()?.to_string(), message["message"].as_str()?.to_string())))
+        .collect();
+    let lines: Vec<&str> = tailed.iter().map(|(_, line)| line.as_str()).collect();
+    assert_eq!(lines, ["first", "last words", "second"]);
+    assert!(tailed.iter().all(|(source, _)| source == "app"));
+    // the offset into the new file is kept for the next start
+    assert!(scratch.read("tail.json").contains("app.log\""));
+}
@@ -96,7 +96,7 @@ const FullMetricsContainer = ({ metrics }: { metrics: MetricsResponse[] }) => {
 
 const Message = ({ message }: { message: MessageResponse }) => {
     return <div className='text-start'>
-        <span style={{ color: message.error ? '#FF3333' : '#868686' }}>{new Date(message.timestamp).toLocaleString()}: {message.message}</span>
+        <span style={{ color: message.error ? '#FF3333' : '#868686' }}>{new Date(message.timestamp).toLocaleString()}: {message.source && `[${message.source}] `}{message.message}</span>
     </div>
 }
 
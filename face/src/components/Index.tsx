@@ -29,6 +29,7 @@ const StatusContainer = ({
                     }
                 </div>
                 <div className="col-4 text-end">
+                    {status.missing ? <span className="text-warning me-2">{status.missing} samples missing</span> : null}
                     {new Date(last_updated).toLocaleString()}
                 </div>
             </div>
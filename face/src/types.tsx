@@ -2,6 +2,7 @@ export interface MessageResponse {
     timestamp: number;
     message: string;
     error: boolean;
+    source?: string | null;
 }
 
 export interface MetricsResponse {
@@ -26,6 +27,7 @@ export interface IntroductionResponse {
 
 export interface StatusResponse {
     exited: boolean;
+    missing?: number | null;
 }
 
 export interface ExitResponse {